@@ -0,0 +1,6554 @@
+//! Generates SSH keys for deploying with GitHub Actions and rsync.
+//!
+//! [`setup_key`] generates a key and authorizes it in one call, configured
+//! with a [`KeyConfig`]; the individual steps are available as well. The
+//! `sshcode` binary is a thin wrapper around [`run`].
+
+use base64::prelude::*;
+use clap::{CommandFactory, Parser, ValueEnum};
+use clap_complete::Shell;
+use colored::Colorize;
+use crypto_box::aead::OsRng;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::stdin;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use zeroize::Zeroize;
+
+/// Name of the SSH directory inside the home directory, used unless
+/// `--ssh-dir` is given.
+const SSH_DIR_NAME: &str = ".ssh";
+
+/// Name of the `authorized_keys` file, inside the SSH directory, where public
+/// keys are appended. This file controls which SSH keys are allowed to
+/// authenticate.
+const AUTHORIZED_KEYS_FILE: &str = "authorized_keys";
+
+/// Name of the `known_hosts` file, inside the SSH directory, that records
+/// trusted server host keys.
+const KNOWN_HOSTS_FILE: &str = "known_hosts";
+
+/// Name of the SSH client config file, inside the SSH directory, where
+/// `--write-ssh-config` adds host aliases.
+const SSH_CONFIG_FILE: &str = "config";
+
+/// Base URL of the GitHub REST API, unless `GITHUB_API_URL` is set.
+const GITHUB_API_URL: &str = "https://api.github.com";
+
+/// Whether `--quiet` was given, suppressing informational output.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Whether `--verbose` was given, echoing external commands and their output.
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Whether `--progress json` was given, reporting each step on stderr.
+static PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Directory inside the user's config directory that holds the config file.
+const CONFIG_DIR_NAME: &str = "ssh-action-rsync";
+
+/// Name of the TOML config file with default options.
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Marks the expiry date in the comment of a key made with `--expire-days`.
+const EXPIRY_PREFIX: &str = "expires=";
+
+/// The date format of the expiry annotation, e.g. `2025-12-01`.
+const EXPIRY_FORMAT: &str = "%Y-%m-%d";
+
+/// Key name used when the user doesn't provide one.
+const DEFAULT_KEY_NAME: &str = "github-actions";
+
+/// The default key file names `ssh` tries for every server, usually holding
+/// the user's personal key rather than a deploy key.
+const WELL_KNOWN_KEY_NAMES: [&str; 7] = [
+    "id_rsa",
+    "id_ecdsa",
+    "id_ecdsa_sk",
+    "id_ed25519",
+    "id_ed25519_sk",
+    "id_xmss",
+    "id_dsa",
+];
+
+/// Longest key name accepted, leaving room for the `.pub` suffix and any
+/// backups within common file name limits.
+const MAX_KEY_NAME_LEN: usize = 64;
+
+/// Key size used for RSA keys when `--bits` is not given.
+const DEFAULT_RSA_BITS: u32 = 4096;
+
+/// Smallest RSA key size `ssh-keygen` accepts.
+const MIN_RSA_BITS: u32 = 1024;
+
+/// Largest RSA key size `ssh-keygen` accepts.
+const MAX_RSA_BITS: u32 = 16384;
+
+/// Smallest RSA key size that isn't considered weak.
+const MIN_STRONG_RSA_BITS: u32 = 2048;
+
+/// The key types to suggest instead of a weak or deprecated one.
+const RECOMMENDED_KEY_TYPES: &str = "ed25519, or rsa with 4096 bits, is recommended";
+
+/// The type of SSH key to generate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyType {
+    #[default]
+    Rsa,
+    Ed25519,
+    Ecdsa,
+    // An Ed25519 key backed by a FIDO/U2F security key.
+    Ed25519Sk,
+    // An ECDSA key backed by a FIDO/U2F security key.
+    EcdsaSk,
+    // Only accepted so that it can be refused with an explanation.
+    #[value(hide = true)]
+    Dsa,
+}
+
+impl KeyType {
+    /// Returns the name `ssh-keygen` expects for its `-t` argument.
+    fn as_str(&self) -> &'static str {
+        match self {
+            KeyType::Rsa => "rsa",
+            KeyType::Ed25519 => "ed25519",
+            KeyType::Ecdsa => "ecdsa",
+            KeyType::Ed25519Sk => "ed25519-sk",
+            KeyType::EcdsaSk => "ecdsa-sk",
+            KeyType::Dsa => "dsa",
+        }
+    }
+
+    /// Returns whether the key lives on a FIDO/U2F security key, which has to
+    /// be present (and touched) when the key is generated and used.
+    fn is_security_key(&self) -> bool {
+        matches!(self, KeyType::Ed25519Sk | KeyType::EcdsaSk)
+    }
+
+    /// Resolves the key size to pass via `-b` for this key type.
+    ///
+    /// RSA defaults to 4096 bits and only accepts sizes `ssh-keygen` supports.
+    /// ECDSA uses the chosen curve, or a valid curve size, and defaults to
+    /// `nistp256`. Ed25519 and security key types have a fixed size, so a
+    /// requested size is ignored with a warning. DSA is refused outright.
+    ///
+    /// # Arguments
+    /// * `requested` - The size given via `--bits`, if any.
+    /// * `curve` - The curve given via `--curve`, if any.
+    ///
+    /// # Returns
+    /// A `Result<Option<u32>, AppError>` with the size to pass, or an
+    /// `InvalidArgument` error for sizes `ssh-keygen` would reject, a curve
+    /// for a key type other than ECDSA, or a DSA key.
+    fn resolve_bits(
+        &self,
+        requested: Option<u32>,
+        curve: Option<Curve>,
+    ) -> Result<Option<u32>, AppError> {
+        // DSA keys are limited to 1024 bits, and recent OpenSSH versions
+        // neither generate nor accept them.
+        if *self == KeyType::Dsa {
+            return Err(invalid_argument(format!(
+                "DSA keys are deprecated and have been removed from OpenSSH; {}",
+                RECOMMENDED_KEY_TYPES
+            )));
+        }
+
+        if let Some(curve) = curve {
+            if *self != KeyType::Ecdsa {
+                return Err(invalid_argument(format!(
+                    "--curve only applies to ecdsa keys, not {}",
+                    self
+                )));
+            }
+            return Ok(Some(curve.bits()));
+        }
+
+        match (self, requested) {
+            (KeyType::Rsa, None) => Ok(Some(DEFAULT_RSA_BITS)),
+            (KeyType::Rsa, Some(bits)) => {
+                if !(MIN_RSA_BITS..=MAX_RSA_BITS).contains(&bits) || bits % 8 != 0 {
+                    return Err(invalid_argument(format!(
+                        "invalid RSA key size {} (expected a multiple of 8 between {} and {})",
+                        bits, MIN_RSA_BITS, MAX_RSA_BITS
+                    )));
+                }
+                Ok(Some(bits))
+            }
+            (KeyType::Ecdsa, None) => Ok(Some(Curve::default().bits())),
+            (KeyType::Ecdsa, Some(bits)) => match bits {
+                256 | 384 | 521 => Ok(Some(bits)),
+                _ => Err(invalid_argument(format!(
+                    "invalid ECDSA key size {} (expected one of: 256, 384, 521)",
+                    bits
+                ))),
+            },
+            (KeyType::Ed25519 | KeyType::Ed25519Sk | KeyType::EcdsaSk, None) => Ok(None),
+            (KeyType::Ed25519 | KeyType::Ed25519Sk | KeyType::EcdsaSk, Some(_)) => {
+                log_warning(&format!("--bits is ignored for {} keys.", self));
+                Ok(None)
+            }
+            (KeyType::Dsa, _) => unreachable!("DSA keys are refused above"),
+        }
+    }
+
+    /// Returns why a key of this type and size would be weak, if it would.
+    ///
+    /// # Arguments
+    /// * `bits` - The resolved key size, if any.
+    fn weakness(&self, bits: Option<u32>) -> Option<String> {
+        match (self, bits) {
+            (KeyType::Rsa, Some(bits)) if bits < MIN_STRONG_RSA_BITS => Some(format!(
+                "RSA keys smaller than {} bits, like this {}-bit one, are considered weak \
+                 and may be broken; {}",
+                MIN_STRONG_RSA_BITS, bits, RECOMMENDED_KEY_TYPES
+            )),
+            _ => None,
+        }
+    }
+
+    /// Refuses a weak key, such as 1024-bit RSA, unless it is explicitly
+    /// allowed.
+    ///
+    /// # Arguments
+    /// * `bits` - The resolved key size, if any.
+    /// * `allow_weak` - Whether a weak key may be generated anyway.
+    ///
+    /// # Returns
+    /// A `Result<(), AppError>` that is an `InvalidArgument` error for a weak
+    /// key that isn't allowed.
+    fn check_strength(&self, bits: Option<u32>, allow_weak: bool) -> Result<(), AppError> {
+        match self.weakness(bits) {
+            Some(reason) if !allow_weak => Err(invalid_argument(format!(
+                "{}. Pass --i-know-its-weak to generate it anyway",
+                reason
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The file format of a private key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum KeyFormat {
+    // The OpenSSH format `ssh-keygen` writes by default.
+    #[default]
+    Openssh,
+    // The classic PEM format, for older tools that can't read OpenSSH keys.
+    Pem,
+}
+
+impl KeyFormat {
+    /// Checks that `ssh-keygen` can write this key type in this format. PEM
+    /// only exists for RSA and ECDSA keys, and `ssh-keygen` would quietly
+    /// fall back to the OpenSSH format for the others.
+    ///
+    /// # Arguments
+    /// * `key_type` - The type of key to generate.
+    ///
+    /// # Returns
+    /// A `Result<(), AppError>` that is an `InvalidArgument` error for a
+    /// key type without a PEM format.
+    fn check_key_type(&self, key_type: KeyType) -> Result<(), AppError> {
+        match (self, key_type) {
+            (KeyFormat::Pem, KeyType::Rsa | KeyType::Ecdsa) | (KeyFormat::Openssh, _) => Ok(()),
+            (KeyFormat::Pem, _) => Err(invalid_argument(format!(
+                "{} keys can't be written in the PEM format; use rsa or ecdsa",
+                key_type
+            ))),
+        }
+    }
+}
+
+/// The CI service the key is for, which decides where the private key is
+/// stored and what the deployment pipeline looks like.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum CiProvider {
+    // GitHub Actions, with the key in a repository secret.
+    #[default]
+    Github,
+    // GitLab CI/CD, with the key in a file-type CI/CD variable.
+    Gitlab,
+    // Bitbucket Pipelines, with the key base64-encoded in a repository
+    // variable.
+    Bitbucket,
+}
+
+impl CiProvider {
+    /// Where the private key goes, for the marker above the printed key.
+    fn secret_store(&self) -> &'static str {
+        match self {
+            CiProvider::Github => "GitHub Secrets",
+            CiProvider::Gitlab => "GitLab CI/CD variables",
+            CiProvider::Bitbucket => "Bitbucket repository variables",
+        }
+    }
+
+    /// Explains how to store the private key for the pipeline to read.
+    ///
+    /// # Arguments
+    /// * `secret` - The name of the secret or variable.
+    /// * `private_key_path` - The private key file.
+    fn secret_instructions(&self, secret: &str, private_key_path: &Path) -> String {
+        match self {
+            CiProvider::Github => format!(
+                "Add the private key as a repository secret named {} (Settings > Secrets \
+                 and variables > Actions > New repository secret).",
+                secret
+            ),
+            CiProvider::Gitlab => format!(
+                "Add the private key as a CI/CD variable named {} (Settings > CI/CD > \
+                 Variables) of type \"File\", and protect it. It can't be masked, since it \
+                 spans several lines.",
+                secret
+            ),
+            CiProvider::Bitbucket => format!(
+                "Add the private key, base64-encoded, as a secured repository variable named \
+                 {} (Repository settings > Repository variables). Encode it with \
+                 --base64, or with: base64 -w0 {}",
+                secret,
+                shell_quote(&private_key_path.to_string_lossy())
+            ),
+        }
+    }
+
+    /// Builds a minimal pipeline that deploys the repository with rsync over
+    /// SSH using the key: it installs the key, trusts the server's host key
+    /// via `ssh-keyscan`, and syncs the checkout to the remote path.
+    ///
+    /// # Arguments
+    /// * `host` - The deployment server's host name or address.
+    /// * `user` - The user to log in as on the deployment server.
+    /// * `remote_path` - The directory on the deployment server to deploy
+    ///   into.
+    /// * `secret` - The name of the secret or variable with the private key.
+    ///
+    /// # Returns
+    /// The pipeline configuration, as YAML.
+    fn pipeline(&self, host: &str, user: &str, remote_path: &str, secret: &str) -> String {
+        match self {
+            CiProvider::Github => format!(
+                r#"name: Deploy
+
+on:
+  push:
+    branches: [main]
+
+  workflow_dispatch:
+
+jobs:
+  deploy:
+    runs-on: ubuntu-latest
+    env:
+      DEPLOY_HOST: {host}
+      DEPLOY_USER: {user}
+      DEPLOY_PATH: {remote_path}
+
+    steps:
+      - uses: actions/checkout@v4
+      - name: Install SSH key
+        run: |
+          mkdir -p ~/.ssh
+          echo "${{{{ secrets.{secret} }}}}" > ~/.ssh/deploy_key
+          chmod 600 ~/.ssh/deploy_key
+          ssh-keyscan -H "$DEPLOY_HOST" >> ~/.ssh/known_hosts
+      - name: rsync deployment
+        run: rsync -avz --delete -e "ssh -i $HOME/.ssh/deploy_key" ./ "$DEPLOY_USER@$DEPLOY_HOST:$DEPLOY_PATH"
+"#,
+                host = yaml_quote(host),
+                user = yaml_quote(user),
+                remote_path = yaml_quote(remote_path),
+                secret = secret,
+            ),
+            // A file-type variable holds the path of a file with the key.
+            CiProvider::Gitlab => format!(
+                r#"deploy:
+  image: alpine:latest
+  variables:
+    DEPLOY_HOST: {host}
+    DEPLOY_USER: {user}
+    DEPLOY_PATH: {remote_path}
+  before_script:
+    - apk add --no-cache openssh-client rsync
+    - chmod 600 "${secret}"
+    - mkdir -p ~/.ssh
+    - ssh-keyscan -H "$DEPLOY_HOST" >> ~/.ssh/known_hosts
+  script:
+    - rsync -avz --delete -e "ssh -i ${secret}" ./ "$DEPLOY_USER@$DEPLOY_HOST:$DEPLOY_PATH"
+  rules:
+    - if: $CI_COMMIT_BRANCH == "main"
+"#,
+                host = yaml_quote(host),
+                user = yaml_quote(user),
+                remote_path = yaml_quote(remote_path),
+                secret = secret,
+            ),
+            // Steps can't set variables of their own, so the server
+            // details go straight into the commands.
+            CiProvider::Bitbucket => {
+                let destination = format!("{}@{}:{}", user, host, remote_path);
+                let script = [
+                    "apk add --no-cache openssh-client rsync".to_string(),
+                    "mkdir -p ~/.ssh".to_string(),
+                    format!("echo \"${}\" | base64 -d > ~/.ssh/deploy_key", secret),
+                    "chmod 600 ~/.ssh/deploy_key".to_string(),
+                    format!("ssh-keyscan -H {} >> ~/.ssh/known_hosts", shell_quote(host)),
+                    format!(
+                        "rsync -avz --delete -e \"ssh -i $HOME/.ssh/deploy_key\" ./ {}",
+                        shell_quote(&destination)
+                    ),
+                ];
+                let script: String = script
+                    .iter()
+                    .map(|line| format!("            - {}\n", yaml_quote(line)))
+                    .collect();
+                format!(
+                    r#"pipelines:
+  branches:
+    main:
+      - step:
+          name: Deploy
+          image: alpine:latest
+          script:
+{}"#,
+                    script
+                )
+            }
+        }
+    }
+}
+
+/// The format of the `--progress` event stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ProgressFormat {
+    // One JSON object per line on stderr.
+    Json,
+}
+
+/// A step reported by `--progress`, named in `snake_case` in the events.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ProgressStep {
+    // Adding the server's host keys to `known_hosts`, for `--add-known-host`.
+    KnownHost,
+    // Generating a key and adding it to `authorized_keys`.
+    Keygen,
+    // Uploading the private key as a GitHub secret, for `--repo`.
+    Upload,
+    // Writing the deployment workflow, for `--emit-workflow`.
+    Workflow,
+    // Adding a `Host` entry to the SSH config, for `--write-ssh-config`.
+    SshConfig,
+    // Loading the key into ssh-agent, for `--add-to-agent`.
+    Agent,
+    // Installing the public key on a server, for `--install-on`.
+    Install,
+    // Logging in with the key, for `--test-connection`.
+    TestConnection,
+}
+
+/// One `--progress` event, e.g. `{"step":"keygen","status":"done","key":"deploy"}`.
+#[derive(Serialize)]
+struct ProgressEvent<'a> {
+    /// The step the event is about.
+    step: ProgressStep,
+    /// `started`, `done` or `failed`.
+    status: &'static str,
+    /// The key the step is for, unless it is for the whole run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<&'a str>,
+}
+
+/// Runs a step and reports its start and outcome with `--progress`.
+///
+/// # Arguments
+/// * `step` - The step being run.
+/// * `key` - The key it is for, if any.
+/// * `run` - Runs the step.
+///
+/// # Returns
+/// Whatever `run` returns.
+fn track<T, E>(
+    step: ProgressStep,
+    key: Option<&str>,
+    run: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E> {
+    report_progress(step, "started", key);
+    let result = run();
+    report_progress(step, if result.is_ok() { "done" } else { "failed" }, key);
+    result
+}
+
+/// Prints a `--progress` event to stderr, if requested.
+fn report_progress(step: ProgressStep, status: &'static str, key: Option<&str>) {
+    if !PROGRESS.load(Ordering::Relaxed) {
+        return;
+    }
+    let event = ProgressEvent { step, status, key };
+    if let Ok(line) = serde_json::to_string(&event) {
+        eprintln!("{}", line);
+    }
+}
+
+/// The file format of the `--manifest` of generated keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum ManifestFormat {
+    // One JSON object per line.
+    #[default]
+    Jsonl,
+    // Comma-separated values, with a header line.
+    Csv,
+}
+
+/// The elliptic curve of an ECDSA key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum Curve {
+    #[default]
+    Nistp256,
+    Nistp384,
+    Nistp521,
+}
+
+impl Curve {
+    /// Returns the key size `ssh-keygen` selects this curve with via `-b`.
+    fn bits(&self) -> u32 {
+        match self {
+            Curve::Nistp256 => 256,
+            Curve::Nistp384 => 384,
+            Curve::Nistp521 => 521,
+        }
+    }
+}
+
+/// Errors that can stop the tool.
+#[derive(Debug)]
+pub enum AppError {
+    /// The `ssh-keygen` binary could not be found.
+    SshKeygenNotFound,
+    /// `ssh-keygen` ran but failed, with its error output.
+    KeygenFailed(String),
+    /// A file or process operation failed.
+    Io(io::Error),
+    /// The chosen key name can't be used as a file name, with the reason.
+    InvalidKeyName(String),
+    /// A command-line option or prompt answer was invalid.
+    InvalidArgument(String),
+    /// Connecting to a server with the generated key failed, with the reason.
+    ConnectionFailed(String),
+    /// A GitHub API request failed, with the reason.
+    GitHubApi(String),
+    /// The config file couldn't be read or parsed, with the reason.
+    InvalidConfig(String),
+    /// The public key file doesn't belong to the private key next to it.
+    KeyPairMismatch(PathBuf),
+    /// The user declined to go ahead, with what was left as it was.
+    Aborted(String),
+    /// Some `--doctor` checks failed, with how many.
+    ChecksFailed(usize),
+    /// `--check` found the key missing or unauthorized, with the reason.
+    /// Like the passing check, this prints nothing by default.
+    CheckFailed(String),
+    /// Some of several requested keys could not be set up.
+    KeysFailed {
+        /// The names of the keys that failed.
+        failed: Vec<String>,
+        /// The names of the keys that were set up.
+        succeeded: Vec<String>,
+        /// How many keys were requested.
+        total: usize,
+    },
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::SshKeygenNotFound => {
+                write!(f, "ssh-keygen not found; install OpenSSH client tools")?;
+                if cfg!(windows) {
+                    write!(
+                        f,
+                        " (on Windows, enable the OpenSSH Client optional feature and make sure it is on PATH)"
+                    )?;
+                }
+                Ok(())
+            }
+            AppError::KeygenFailed(stderr) => {
+                write!(f, "error generating SSH key: {}", redact(stderr))
+            }
+            AppError::Io(err) => write!(f, "{}", err),
+            AppError::InvalidKeyName(reason) => write!(f, "invalid key name: {}", reason),
+            AppError::InvalidArgument(message) => write!(f, "{}", message),
+            AppError::ConnectionFailed(reason) => write!(f, "SSH connection failed: {}", reason),
+            AppError::GitHubApi(reason) => write!(f, "GitHub secret upload failed: {}", reason),
+            AppError::InvalidConfig(reason) => write!(f, "invalid config file: {}", reason),
+            AppError::KeyPairMismatch(public_key_path) => write!(
+                f,
+                "{} does not match its private key; delete both files and generate the key again",
+                public_key_path.display()
+            ),
+            AppError::Aborted(outcome) => write!(f, "aborted: {}", outcome),
+            AppError::ChecksFailed(failed) => {
+                write!(f, "{} check(s) failed; see the fixes above", failed)
+            }
+            AppError::CheckFailed(reason) => write!(f, "{}", reason),
+            AppError::KeysFailed {
+                failed,
+                succeeded,
+                total,
+            } => {
+                write!(
+                    f,
+                    "{} of {} keys could not be set up: {}",
+                    failed.len(),
+                    total,
+                    failed.join(", ")
+                )?;
+                if !succeeded.is_empty() {
+                    write!(f, " (set up: {})", succeeded.join(", "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl AppError {
+    /// Returns the process exit code for this error, so scripts can tell
+    /// the kinds of failure apart. The codes are listed in `--help`.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AppError::InvalidArgument(_)
+            | AppError::InvalidKeyName(_)
+            | AppError::InvalidConfig(_) => 2,
+            AppError::SshKeygenNotFound => 3,
+            AppError::KeygenFailed(_) | AppError::KeyPairMismatch(_) => 4,
+            AppError::Io(_) => 5,
+            AppError::ConnectionFailed(_) => 6,
+            AppError::GitHubApi(_) => 7,
+            AppError::Aborted(_)
+            | AppError::ChecksFailed(_)
+            | AppError::CheckFailed(_)
+            | AppError::KeysFailed { .. } => 1,
+        }
+    }
+
+    /// Returns whether the error is an expected answer rather than a
+    /// problem, and so shouldn't be reported, like a failed `--check`.
+    pub fn is_silent(&self) -> bool {
+        matches!(self, AppError::CheckFailed(_))
+    }
+}
+
+impl std::error::Error for AppError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AppError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(err: io::Error) -> Self {
+        AppError::Io(err)
+    }
+}
+
+/// The exit codes from [`AppError::exit_code`], for `--help`.
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  Success
+  1  Aborted, some of several keys failed, --doctor found problems, or
+     --check found the key missing
+  2  Invalid arguments, key name or config file
+  3  ssh-keygen not found
+  4  ssh-keygen failed
+  5  File system or permission error
+  6  SSH connection failed
+  7  GitHub API request failed";
+
+/// Command-line options accepted by the tool.
+#[derive(Parser, Default)]
+#[command(
+    disable_version_flag = true,
+    about = "Generate an SSH key for deploying with GitHub Actions and rsync.",
+    after_help = EXIT_CODES_HELP
+)]
+struct Args {
+    /// Print the version, and the OpenSSH version `ssh-keygen` comes from.
+    #[arg(short = 'V', long)]
+    version: bool,
+    /// The type of key to generate (default: rsa).
+    #[arg(long, value_enum)]
+    key_type: Option<KeyType>,
+    /// The key size to generate, in bits.
+    #[arg(long)]
+    bits: Option<u32>,
+    /// The private key file format.
+    #[arg(long, value_enum, default_value_t)]
+    format: KeyFormat,
+    /// The curve for ECDSA keys (default: nistp256).
+    #[arg(long, value_enum, conflicts_with = "bits")]
+    curve: Option<Curve>,
+    /// The key name; repeat it or separate names with commas for several
+    /// keys. Without it, $SSH_KEY_NAME is used, then a prompt (on a terminal
+    /// only), then `--default-name`.
+    #[arg(long, env = "SSH_KEY_NAME", value_delimiter = ',')]
+    name: Vec<String>,
+    /// The key name to fall back on when none is given or entered (default:
+    /// `github-actions`).
+    #[arg(long, value_name = "NAME")]
+    default_name: Option<String>,
+    /// Create this many keys, named `<prefix>-1` to `<prefix>-N`, instead of
+    /// the named ones.
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "prefix",
+        conflicts_with = "rotate"
+    )]
+    batch: Option<u32>,
+    /// The name to number the keys after, for `--batch`.
+    #[arg(long, value_name = "NAME", requires = "batch")]
+    prefix: Option<String>,
+    /// Never prompt on stdin; use the default name if none is given.
+    #[arg(long)]
+    non_interactive: bool,
+    /// The passphrase for the private key.
+    #[arg(long, conflicts_with = "ask_passphrase")]
+    passphrase: Option<String>,
+    /// Read the passphrase from the terminal without echoing it.
+    #[arg(long)]
+    ask_passphrase: bool,
+    /// The number of KDF rounds to protect the passphrase with (`ssh-keygen
+    /// -a`); more rounds slow down brute-forcing it.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    kdf_rounds: Option<u32>,
+    /// Overwrite an existing key with the same name without asking.
+    #[arg(long)]
+    force: bool,
+    /// Reuse an existing key with the same name, type and size instead of
+    /// generating a new one, unless `--force` is given.
+    #[arg(long, conflicts_with = "rotate")]
+    idempotent: bool,
+    /// Allow creating keys as root in non-interactive mode.
+    #[arg(long)]
+    allow_root: bool,
+    /// Give the key files and SSH directory to this user, and their primary
+    /// group or the one given, after creating them (Unix only).
+    #[arg(long, value_name = "USER[:GROUP]")]
+    owner: Option<String>,
+    /// Generate a weak key, such as 1024-bit RSA, anyway.
+    #[arg(long)]
+    i_know_its_weak: bool,
+    /// Write a deployment workflow for the `--ci` service (GitHub Actions by
+    /// default) to this file.
+    #[arg(long, value_name = "PATH")]
+    emit_workflow: Option<String>,
+    /// Print how to store the key and a deployment pipeline for this CI
+    /// service.
+    #[arg(long, value_enum, value_name = "PROVIDER")]
+    ci: Option<CiProvider>,
+    /// The deployment server's host name or address.
+    #[arg(long)]
+    host: Option<String>,
+    /// The user to log in as on the deployment server.
+    #[arg(long)]
+    user: Option<String>,
+    /// The directory on the deployment server to deploy into.
+    #[arg(long, value_name = "PATH")]
+    remote_path: Option<String>,
+    /// Print the public key to add to the server's `authorized_keys`.
+    #[arg(long)]
+    show_public_key: bool,
+    /// Print only the public key to stdout, for piping into another tool,
+    /// and leave the private key in its file unless it is delivered
+    /// otherwise.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "json", "print_private_key", "show_public_key", "print_rsync",
+            "print_action_inputs", "list", "remove", "prune_expired", "print_public_key",
+            "check", "doctor", "show_config",
+        ]
+    )]
+    stdout_pubkey: bool,
+    /// Print the public key of this existing key instead of generating one.
+    #[arg(
+        long,
+        value_name = "NAME",
+        conflicts_with_all = ["list", "remove", "rotate", "prune_expired", "show_config"]
+    )]
+    print_public_key: Option<String>,
+    /// Exit with 0 if this key's files exist, or 1 if not, without changing
+    /// or printing anything.
+    #[arg(
+        long,
+        value_name = "NAME",
+        conflicts_with_all = [
+            "list", "remove", "rotate", "prune_expired", "print_public_key", "show_config",
+            "doctor", "json",
+        ]
+    )]
+    check: Option<String>,
+    /// With `--check`, also require the key to be in `authorized_keys`.
+    #[arg(long, requires = "check")]
+    check_authorized: bool,
+    /// Print the private key even when stdout is not a terminal.
+    #[arg(long)]
+    print_private_key: bool,
+    /// Print the private key without asking first.
+    #[arg(long, visible_alias = "no-confirm")]
+    yes: bool,
+    /// Write the private key to this file instead of printing it.
+    #[arg(long, value_name = "PATH")]
+    output: Option<String>,
+    /// Generate the key in a temporary directory, in memory where possible,
+    /// and wipe and delete its files once it has been delivered.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "rotate", "list", "remove", "prune_expired", "print_public_key",
+            "subdir", "write_ssh_config", "print_rsync",
+        ]
+    )]
+    ephemeral: bool,
+    /// Write each private key to a file named by this template instead of
+    /// printing it, replacing `{name}`, `{type}` and `{date}`.
+    #[arg(long, value_name = "TEMPLATE", conflicts_with = "output")]
+    output_template: Option<String>,
+    /// Copy the private key to the system clipboard instead of printing it.
+    #[arg(long)]
+    clipboard: bool,
+    /// The directory for key files and `authorized_keys`, instead of `~/.ssh`.
+    #[arg(long, value_name = "PATH")]
+    ssh_dir: Option<String>,
+    /// Fail if the SSH directory doesn't exist instead of creating it.
+    #[arg(long)]
+    no_create_dir: bool,
+    /// Show what would be done without changing anything.
+    #[arg(long)]
+    dry_run: bool,
+    /// Don't add the public key to the local `authorized_keys`.
+    #[arg(long)]
+    no_authorize: bool,
+    /// Make the new key the only one in `authorized_keys`, after backing it
+    /// up, instead of adding it to the others.
+    #[arg(long, conflicts_with_all = ["no_authorize", "no_backup", "rotate"])]
+    replace_authorized_keys: bool,
+    /// Add the public key to this file instead of `authorized_keys` in the
+    /// SSH directory, e.g. for a custom `AuthorizedKeysFile`.
+    #[arg(long, value_name = "PATH", conflicts_with = "no_authorize")]
+    authorized_keys: Option<String>,
+    /// Create a missing parent directory of `--authorized-keys` without
+    /// asking, with mode 0755.
+    #[arg(long, requires = "authorized_keys")]
+    create_parents: bool,
+    /// Add the public key to `authorized_keys` even if the config file
+    /// turns that off.
+    #[arg(long, conflicts_with = "no_authorize")]
+    authorize: bool,
+    /// Only let the key run this command, via a `command="..."` option in
+    /// `authorized_keys`.
+    #[arg(long, value_name = "CMD")]
+    restrict_command: Option<String>,
+    /// Don't allocate a terminal for logins with the key.
+    #[arg(long)]
+    no_pty: bool,
+    /// Don't allow port, agent or X11 forwarding with the key.
+    #[arg(long)]
+    no_forwarding: bool,
+    /// Don't back up `authorized_keys` before modifying it.
+    #[arg(long)]
+    no_backup: bool,
+    /// Write to the file `authorized_keys` links to when it is a symbolic
+    /// link, instead of refusing.
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// Output the private key as a single line of base64.
+    #[arg(long)]
+    base64: bool,
+    /// Append the public key to `authorized_keys` on this server over SSH.
+    #[arg(long, value_name = "USER@HOST")]
+    install_on: Option<String>,
+    /// Log in to the `--install-on` server with its password, for servers
+    /// that don't accept any key yet. `ssh` asks for it on the terminal.
+    #[arg(long, requires = "install_on")]
+    password_auth: bool,
+    /// Check that the key can log in to this server after setup.
+    #[arg(long, value_name = "USER@HOST")]
+    test_connection: Option<String>,
+    /// Scan this server's host keys and add them to `known_hosts`.
+    #[arg(long, value_name = "HOST")]
+    add_known_host: Option<String>,
+    /// Load the key into the running ssh-agent with `ssh-add`.
+    #[arg(long)]
+    add_to_agent: bool,
+    /// Print an rsync command that deploys with the key.
+    #[arg(long)]
+    print_rsync: bool,
+    /// Print the `with:` inputs for an rsync deployment action step.
+    #[arg(long)]
+    print_action_inputs: bool,
+    /// The local directory to deploy, for `--print-rsync` and
+    /// `--print-action-inputs`.
+    #[arg(long, value_name = "PATH")]
+    local_path: Option<String>,
+    /// Include `--delete` in the printed rsync command or action inputs.
+    #[arg(long)]
+    delete: bool,
+    /// Add a `Host` entry for the key to the SSH config file.
+    #[arg(long)]
+    write_ssh_config: bool,
+    /// The `Host` alias to use with `ssh`, for `--write-ssh-config`.
+    #[arg(long, value_name = "NAME", requires = "write_ssh_config")]
+    alias: Option<String>,
+    /// The key comment, instead of the key name.
+    #[arg(long)]
+    comment: Option<String>,
+    /// Use `<name>@<host>` as the key comment, unless `--comment` is given.
+    #[arg(long, value_name = "HOST")]
+    comment_host: Option<String>,
+    /// Use `<name>@<owner>/<repo>` as the key comment, from the current git
+    /// repository's `origin` remote, unless `--comment` is given.
+    #[arg(long, conflicts_with = "comment_host")]
+    comment_from_git: bool,
+    /// Put the key files in this subdirectory of the SSH directory, e.g.
+    /// `deploy-keys/prod`.
+    #[arg(long, value_name = "PATH")]
+    subdir: Option<String>,
+    /// List the keys in the SSH directory instead of generating one.
+    #[arg(long)]
+    list: bool,
+    /// Print the settings a run would use, and where each one comes from,
+    /// instead of generating a key.
+    #[arg(long, conflicts_with_all = ["list", "remove", "rotate", "prune_expired"])]
+    show_config: bool,
+    /// Check the environment for common problems, such as a missing
+    /// `ssh-keygen` or loose permissions, without changing anything.
+    #[arg(
+        long,
+        conflicts_with_all = ["list", "remove", "rotate", "prune_expired", "show_config", "json"]
+    )]
+    doctor: bool,
+    /// Delete this key and revoke it from `authorized_keys`.
+    #[arg(long, value_name = "NAME")]
+    remove: Option<String>,
+    /// Replace this key with a new one, backing up the old key and swapping
+    /// it out in `authorized_keys`.
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["list", "remove"])]
+    rotate: Option<String>,
+    /// Note in the key comment that the key expires after this many days.
+    #[arg(long, value_name = "DAYS", value_parser = clap::value_parser!(u32).range(1..))]
+    expire_days: Option<u32>,
+    /// Remove keys whose `--expire-days` date has passed, and revoke them
+    /// from `authorized_keys`.
+    #[arg(long, conflicts_with_all = ["list", "remove", "rotate"])]
+    prune_expired: bool,
+    /// Suppress informational output, leaving only results and errors.
+    #[arg(long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Echo the `ssh-keygen` command and its output to stderr.
+    #[arg(long)]
+    verbose: bool,
+    /// Retry starting `ssh-keygen` this many times after a transient failure.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retries: u32,
+    /// Upload the private key as a secret of this GitHub repository, using
+    /// `GITHUB_TOKEN`.
+    #[arg(long, value_name = "OWNER/NAME")]
+    repo: Option<String>,
+    /// The repository secret name, instead of one derived from the key name.
+    #[arg(long, value_name = "NAME")]
+    secret_name: Option<String>,
+    /// Print the results as a JSON object instead of prose.
+    #[arg(
+        long,
+        conflicts_with_all = ["dry_run", "list", "remove", "prune_expired", "print_public_key"]
+    )]
+    json: bool,
+    /// Report each step as it starts and finishes, as JSON lines on stderr.
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    progress: Option<ProgressFormat>,
+    /// Append a line about each generated key, without the private key, to
+    /// this file.
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<String>,
+    /// Append the repository, name, fingerprint and creation time of each
+    /// generated key to this inventory file.
+    #[arg(long, value_name = "PATH")]
+    manifest: Option<String>,
+    /// The format of the `--manifest` file.
+    #[arg(long, value_enum, default_value_t, requires = "manifest")]
+    manifest_format: ManifestFormat,
+    /// The repository to record in the `--manifest`, instead of `--repo`.
+    #[arg(long, value_name = "OWNER/NAME", requires = "manifest")]
+    manifest_repo: Option<String>,
+    /// Read default options from this TOML file instead of the default
+    /// config file.
+    #[arg(long, value_name = "PATH")]
+    config: Option<String>,
+    /// Print a completion script for this shell and exit.
+    #[arg(long, value_name = "SHELL", hide = true)]
+    completions: Option<Shell>,
+}
+
+impl Args {
+    /// Returns the type of key to generate, RSA unless one was chosen.
+    fn key_type(&self) -> KeyType {
+        self.key_type.unwrap_or_default()
+    }
+
+    /// Fills in options that weren't given on the command line from the
+    /// config file.
+    ///
+    /// # Arguments
+    /// * `config` - The defaults read from the config file.
+    fn apply_config(&mut self, config: Config) {
+        self.key_type = self.key_type.or(config.key_type);
+        self.bits = self.bits.or(config.bits);
+        self.ssh_dir = self.ssh_dir.take().or(config.ssh_dir);
+        self.default_name = self.default_name.take().or(config.default_name);
+        if config.authorize == Some(false) && !self.authorize {
+            self.no_authorize = true;
+        }
+    }
+
+    /// Returns the key name to use when none is given or entered:
+    /// `--default-name`, or `github-actions`.
+    fn default_key_name(&self) -> &str {
+        self.default_name
+            .as_deref()
+            .map(str::trim)
+            .unwrap_or(DEFAULT_KEY_NAME)
+    }
+
+    /// Whether the user may be prompted on stdin.
+    ///
+    /// Prompts are skipped with `--non-interactive` or `--json`, and also
+    /// when stdin isn't a terminal, so scripts and pipelines never hang
+    /// waiting on one.
+    fn is_interactive(&self) -> bool {
+        !self.non_interactive && !self.json && stdin().is_terminal()
+    }
+}
+
+/// Prints the tool's version for `--version`, with the `ssh-keygen` it
+/// would run and the OpenSSH version that comes from, since its behavior
+/// varies between releases. A missing `ssh-keygen` is reported rather than
+/// treated as an error.
+fn print_version() {
+    println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    let Some(ssh_keygen) = find_on_path("ssh-keygen") else {
+        println!("ssh-keygen: not found on PATH");
+        return;
+    };
+
+    // `ssh-keygen` can't report its version, but `ssh -V` next to it can.
+    let ssh = ssh_keygen.with_file_name(if cfg!(windows) { "ssh.exe" } else { "ssh" });
+    let openssh = Command::new(ssh)
+        .arg("-V")
+        .stdin(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        // The version goes to stderr.
+        .map(|output| String::from_utf8_lossy(&output.stderr).trim().to_string())
+        .filter(|version| !version.is_empty())
+        .unwrap_or_else(|| "unknown OpenSSH version".to_string());
+    println!("ssh-keygen: {} ({})", ssh_keygen.display(), openssh);
+}
+
+/// Prints a completion script for `shell` to stdout.
+///
+/// # Arguments
+/// * `shell` - The shell to generate completions for.
+fn print_completions(shell: Shell) {
+    let mut command = Args::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+}
+
+/// Default options read from the config file.
+///
+/// Every setting is optional; anything left out keeps its usual default.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    /// The type of key to generate.
+    key_type: Option<KeyType>,
+    /// The key size to generate, in bits.
+    bits: Option<u32>,
+    /// The directory for key files and `authorized_keys`.
+    ssh_dir: Option<String>,
+    /// Whether to add the public key to the local `authorized_keys`.
+    authorize: Option<bool>,
+    /// The key name to fall back on when none is given or entered.
+    default_name: Option<String>,
+}
+
+/// Returns the default config file location,
+/// `~/.config/ssh-action-rsync/config.toml` on Linux.
+fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join(CONFIG_DIR_NAME).join(CONFIG_FILE_NAME))
+}
+
+/// Reads the config file given via `--config`, or the default one.
+///
+/// A missing default config file just means there are no defaults to
+/// apply, but a file named with `--config` has to exist.
+///
+/// # Arguments
+/// * `path` - The file given via `--config`, if any. A leading `~` is
+///   expanded.
+///
+/// # Returns
+/// A `Result<Option<(PathBuf, Config)>, AppError>` with the file that was
+/// read and its settings, `None` when there is no config file, or an
+/// `InvalidConfig` error if it can't be read or parsed.
+fn load_config(path: Option<&str>) -> Result<Option<(PathBuf, Config)>, AppError> {
+    let (path, required) = match path {
+        Some(path) => (resolve_home_path(path)?, true),
+        None => match default_config_path() {
+            Some(path) => (path, false),
+            None => return Ok(None),
+        },
+    };
+
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound && !required => return Ok(None),
+        Err(err) => {
+            return Err(AppError::InvalidConfig(format!(
+                "{}: {}",
+                path.display(),
+                err
+            )))
+        }
+    };
+    log_verbose(&format!("Using config file {}", path.display()));
+
+    match toml::from_str(&contents) {
+        Ok(config) => Ok(Some((path, config))),
+        Err(err) => Err(AppError::InvalidConfig(format!(
+            "{}: {}",
+            path.display(),
+            err
+        ))),
+    }
+}
+
+/// The settings a run would use, printed by `--show-config`. Each
+/// `*_source` field says where the value above it comes from: a
+/// command-line option, the config file or the default.
+#[derive(Serialize)]
+struct EffectiveConfig {
+    /// The config file that was read, if any.
+    config_file: Option<PathBuf>,
+    /// The user's home directory, if it could be determined.
+    home_dir: Option<PathBuf>,
+    home_dir_source: &'static str,
+    /// The directory for the key files.
+    ssh_dir: PathBuf,
+    ssh_dir_source: &'static str,
+    /// The file public keys are added to.
+    authorized_keys_path: PathBuf,
+    authorized_keys_source: &'static str,
+    /// The type of key to generate.
+    key_type: String,
+    key_type_source: &'static str,
+    /// The key size in bits, or `None` for key types without one.
+    bits: Option<u32>,
+    bits_source: &'static str,
+}
+
+/// Prints the SSH directory, `authorized_keys` file, key type and size a
+/// run would use, and where each one comes from, for `--show-config`.
+/// Nothing is generated or changed.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options, before the config file is
+///   applied.
+/// * `config` - The config file that was read and its settings, if any.
+///
+/// # Returns
+/// A `Result<(), AppError>` indicating success or failure.
+fn show_config(mut args: Args, config: Option<(PathBuf, Config)>) -> Result<(), AppError> {
+    let (config_file, config) = config.unzip();
+    let config = config.unwrap_or_default();
+
+    // Work out the sources before the config file fills in the options.
+    let source = |flag: &'static str, given: bool, in_config: bool| {
+        if given {
+            flag
+        } else if in_config {
+            "config file"
+        } else {
+            "default"
+        }
+    };
+    let ssh_dir_source = source(
+        "--ssh-dir",
+        args.ssh_dir.is_some(),
+        config.ssh_dir.is_some(),
+    );
+    let key_type_source = source(
+        "--key-type",
+        args.key_type.is_some(),
+        config.key_type.is_some(),
+    );
+    let mut bits_source = match args.curve {
+        Some(_) => "--curve",
+        None => source("--bits", args.bits.is_some(), config.bits.is_some()),
+    };
+    let authorized_keys_source = match args.authorized_keys {
+        Some(_) => "--authorized-keys",
+        None => "SSH directory",
+    };
+    args.apply_config(config);
+
+    let ssh_dir = resolve_ssh_dir(&args)?;
+    let bits = args.key_type().resolve_bits(args.bits, args.curve)?;
+    if bits.is_none() {
+        bits_source = "not used by this key type";
+    }
+    // `dirs` uses `$HOME` when it is set and not empty, and otherwise asks
+    // the system.
+    let home_dir_source = if cfg!(windows) {
+        "user profile"
+    } else if env::var_os("HOME").is_some_and(|home| !home.is_empty()) {
+        "$HOME"
+    } else {
+        "user database"
+    };
+    let effective = EffectiveConfig {
+        authorized_keys_path: resolve_authorized_keys_path(&args, &ssh_dir)?,
+        authorized_keys_source,
+        config_file,
+        home_dir: dirs::home_dir(),
+        home_dir_source,
+        ssh_dir,
+        ssh_dir_source,
+        key_type: args.key_type().to_string(),
+        key_type_source,
+        bits,
+        bits_source,
+    };
+
+    if args.json {
+        return Ok(print_json_report(&effective)?);
+    }
+
+    let config_file = match (&effective.config_file, default_config_path()) {
+        (Some(path), _) => path.display().to_string(),
+        (None, Some(default)) => format!("none ({} not found)", default.display()),
+        (None, None) => "none".to_string(),
+    };
+    let home_dir = match &effective.home_dir {
+        Some(home) => format!("{} ({})", home.display(), effective.home_dir_source),
+        None => "could not be determined".to_string(),
+    };
+    let bits = match effective.bits {
+        Some(bits) => format!("{} ({})", bits, effective.bits_source),
+        None => effective.bits_source.to_string(),
+    };
+    println!("Config file:      {}", config_file);
+    println!("Home directory:   {}", home_dir);
+    println!(
+        "SSH directory:    {} ({})",
+        effective.ssh_dir.display(),
+        effective.ssh_dir_source
+    );
+    println!(
+        "authorized_keys:  {} ({})",
+        effective.authorized_keys_path.display(),
+        effective.authorized_keys_source
+    );
+    println!(
+        "Key type:         {} ({})",
+        effective.key_type, effective.key_type_source
+    );
+    println!("Bits:             {}", bits);
+
+    Ok(())
+}
+
+/// Returns the directory for the key files: `--ssh-dir` (or the config
+/// file's `ssh-dir`) with a leading `~` expanded, or `~/.ssh`.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options, with the config file
+///   applied.
+///
+/// # Returns
+/// An `io::Result<PathBuf>` with the SSH directory.
+fn resolve_ssh_dir(args: &Args) -> io::Result<PathBuf> {
+    match &args.ssh_dir {
+        Some(dir) => resolve_home_path(dir),
+        None => Ok(home_dir()?.join(SSH_DIR_NAME)),
+    }
+}
+
+/// Returns the file public keys are added to: `--authorized-keys` with
+/// environment variables and a leading `~` expanded, or `authorized_keys`
+/// in the SSH directory.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+/// * `ssh_dir` - The SSH directory.
+///
+/// # Returns
+/// A `Result<PathBuf, AppError>` with the path, or an `InvalidArgument`
+/// error for an unset environment variable.
+fn resolve_authorized_keys_path(args: &Args, ssh_dir: &Path) -> Result<PathBuf, AppError> {
+    match &args.authorized_keys {
+        Some(path) => Ok(resolve_home_path(&expand_env_vars(path)?)?),
+        None => Ok(ssh_dir.join(AUTHORIZED_KEYS_FILE)),
+    }
+}
+
+/// Builds an `InvalidArgument` error carrying the given message.
+fn invalid_argument(message: String) -> AppError {
+    AppError::InvalidArgument(message)
+}
+
+/// Handles SSH key generation and setup.
+///
+/// This function will:
+/// 1. Use the names given via `--name`, or ask the user for a name for the
+///    SSH key. If no name is provided, it defaults to `--default-name`, or
+///    `github-actions`.
+/// 2. Check everything that could fail before any key is generated: the
+///    options, output files and GitHub access.
+/// 3. Set up each key with [`setup_key`], then deliver its private key and
+///    carry out the other requested steps.
+/// 4. When several keys are requested, keep going past a failed key and
+///    report every failure at the end.
+///
+/// # Returns
+/// A `Result<(), AppError>` indicating whether the process completed
+/// successfully.
+pub fn run() -> Result<(), AppError> {
+    let mut args = Args::parse();
+    // The JSON report replaces the usual prose, so keep stdout free of it.
+    QUIET.store(args.quiet || args.json, Ordering::Relaxed);
+    VERBOSE.store(args.verbose, Ordering::Relaxed);
+    PROGRESS.store(
+        args.progress == Some(ProgressFormat::Json),
+        Ordering::Relaxed,
+    );
+
+    if args.version {
+        print_version();
+        return Ok(());
+    }
+    if let Some(shell) = args.completions {
+        print_completions(shell);
+        return Ok(());
+    }
+
+    let config = load_config(args.config.as_deref())?;
+    if args.show_config {
+        return show_config(args, config);
+    }
+    // Command-line options win over the config file.
+    if let Some((_, config)) = config {
+        args.apply_config(config);
+    }
+
+    // The checks cover what the steps below would stop at, so run them
+    // first.
+    if args.doctor {
+        return run_doctor(&args);
+    }
+
+    // Fail fast before prompting if there's no `ssh-keygen` to run. Checking
+    // for a key doesn't run it.
+    if args.check.is_none() {
+        locate_ssh_keygen()?;
+    }
+    // Every key and config path lives under the SSH directory, so resolve it
+    // before doing anything else.
+    let ssh_dir = resolve_ssh_dir(&args)?;
+    let ssh_dir = ssh_dir.as_path();
+    let authorized_keys_path = resolve_authorized_keys_path(&args, ssh_dir)?;
+    // Only the key files go in `--subdir`; `authorized_keys` and the other
+    // SSH files stay where SSH looks for them.
+    let subdir = args.subdir.as_deref().map(PathBuf::from);
+    if let Some(subdir) = &subdir {
+        validate_subdir(subdir)?;
+    }
+    let key_dir = match &subdir {
+        Some(subdir) => ssh_dir.join(subdir),
+        None => ssh_dir.to_path_buf(),
+    };
+    let key_dir = key_dir.as_path();
+
+    if args.list {
+        return list_keys(key_dir);
+    }
+
+    if let Some(name) = &args.remove {
+        return remove_key(&args, key_dir, &authorized_keys_path, name.trim());
+    }
+
+    if let Some(name) = &args.print_public_key {
+        return print_existing_public_key(&args, key_dir, name.trim());
+    }
+
+    if let Some(name) = &args.check {
+        return check_key(&args, key_dir, &authorized_keys_path, name.trim());
+    }
+
+    if args.prune_expired {
+        return prune_expired_keys(&args, key_dir, &authorized_keys_path);
+    }
+
+    // Rather than silently picking RSA, let the user choose, nudging them
+    // towards ed25519.
+    if args.key_type.is_none() && args.is_interactive() {
+        args.key_type = Some(prompt_key_type()?);
+    }
+    // The repository takes the place of `--comment-host`, which it
+    // conflicts with.
+    if args.comment_from_git && args.comment.is_none() {
+        args.comment_host = git_origin_repo();
+    }
+    let args = args;
+
+    ensure_root_is_intended(&args, key_dir)?;
+    let owner = args.owner.as_deref().map(resolve_owner).transpose()?;
+
+    let bits = args.key_type().resolve_bits(args.bits, args.curve)?;
+    args.key_type().check_strength(bits, args.i_know_its_weak)?;
+    if let Some(reason) = args.key_type().weakness(bits) {
+        log_warning(&format!("{}.", reason));
+    }
+
+    let key_names = resolve_key_names(&args)?;
+    confirm_well_known_names(&args, &key_names)?;
+    // Refuse a symlinked `authorized_keys` before any key is generated.
+    if !args.no_authorize {
+        authorized_keys_target(&authorized_keys_path, args.follow_symlinks)?;
+    }
+    if args.replace_authorized_keys && !args.dry_run {
+        confirm_replacing_authorized_keys(&args, &authorized_keys_path)?;
+    }
+    // `ssh` asks for the password itself, so there has to be someone to ask.
+    if args.password_auth && !args.dry_run && !args.is_interactive() {
+        return Err(invalid_argument(
+            "--password-auth needs a terminal for ssh to ask for the password".to_string(),
+        ));
+    }
+    if key_names.len() > 1 {
+        ensure_single_key_options(&args)?;
+    }
+    let passphrase = resolve_passphrase(&args)?;
+    if args.kdf_rounds.is_some() && passphrase.is_empty() {
+        log_warning("--kdf-rounds has no effect without a passphrase.");
+    }
+    let key_options = authorized_key_options(&args)?;
+    let workflow = resolve_workflow_settings(&args)?;
+    let rsync = resolve_rsync_settings(&args)?;
+    let ssh_config = resolve_ssh_config_settings(&args)?;
+    let github = resolve_github_target(&args)?;
+
+    // Printing is the fallback for the private key, so refuse up front when
+    // it isn't allowed and nothing else would receive the key.
+    let delivered_elsewhere = args.output.is_some()
+        || args.output_template.is_some()
+        || args.clipboard
+        || args.repo.is_some();
+    if !delivered_elsewhere && !args.stdout_pubkey && !may_print_private_key(&args) {
+        return Err(invalid_argument(
+            "refusing to print the private key because stdout is not a terminal \
+             (it could end up in a log); pass --output <file> or --print-private-key"
+                .to_string(),
+        ));
+    }
+    // With stdout kept for the public key, the key file is the only copy.
+    if !delivered_elsewhere && args.stdout_pubkey && args.ephemeral {
+        return Err(invalid_argument(
+            "--ephemeral with --stdout-pubkey would delete the only copy of the private key; \
+             pass --output <file>, --clipboard or --repo"
+                .to_string(),
+        ));
+    }
+
+    // Check output files up front so a clash doesn't waste a generated key.
+    let mut outputs: Vec<String> = Vec::new();
+    for key_name in &key_names {
+        let Some(output) = private_key_output(&args, key_name)? else {
+            continue;
+        };
+        if outputs.contains(&output) {
+            return Err(invalid_argument(format!(
+                "--output-template names {} for more than one key; include {{name}}",
+                output
+            )));
+        }
+        ensure_can_write(&output, args.force)?;
+        outputs.push(output);
+    }
+    if let Some(workflow) = &workflow {
+        ensure_can_write(&workflow.path, args.force)?;
+    }
+    // Creating the log file now also makes sure it can be written to.
+    if let (Some(log_file), false) = (&args.log_file, args.dry_run) {
+        open_log_file(log_file)?;
+    }
+    if let (Some(manifest), false) = (&args.manifest, args.dry_run) {
+        open_log_file(manifest)?;
+    }
+
+    // Check the token against the repository before generating anything, so
+    // a permission problem doesn't strand a new key.
+    let repo_public_key = match &github {
+        Some(target) if !args.dry_run => Some(fetch_repo_public_key(target)?),
+        _ => None,
+    };
+
+    // Ensure that the .ssh directory exists.
+    ensure_ssh_directory_exists(
+        ssh_dir,
+        subdir.as_deref(),
+        !args.no_create_dir,
+        args.dry_run,
+    )
+    .map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound if args.no_create_dir => io::Error::new(
+            err.kind(),
+            format!("{} by --no-create-dir; create it first", err),
+        ),
+        _ => err,
+    })?;
+    if args.authorized_keys.is_some() && !args.no_authorize {
+        ensure_authorized_keys_parent_exists(&args, &authorized_keys_path)?;
+    }
+
+    // Trust the server before installing on or testing against it, so strict
+    // host key checking doesn't get in the way.
+    if let (Some(host), false) = (&args.add_known_host, args.dry_run) {
+        track(ProgressStep::KnownHost, None, || {
+            add_known_host(ssh_dir, host)
+        })?;
+    }
+
+    // Wiped and deleted when dropped, even if the setup fails.
+    let ephemeral_dir = match (args.ephemeral, args.dry_run) {
+        (true, false) => Some(EphemeralDir::create()?),
+        (true, true) => {
+            eprintln!(
+                "Would generate the keys in a temporary directory and delete them afterwards."
+            );
+            None
+        }
+        (false, _) => None,
+    };
+
+    let plan = SetupPlan {
+        args: &args,
+        ssh_dir,
+        subdir,
+        bits,
+        passphrase,
+        key_options,
+        authorized_keys_path,
+        workflow,
+        rsync,
+        ssh_config,
+        github,
+        repo_public_key,
+        ephemeral_dir: ephemeral_dir.as_ref().map(|dir| dir.path.as_path()),
+        owner,
+    };
+
+    // With a single key any failure is simply the error. With several, keep
+    // going so one bad key doesn't stop the rest, and report what failed. A
+    // batch is reported as a list even when it is a single key.
+    if let ([key_name], None) = (key_names.as_slice(), args.batch) {
+        match setup_and_deliver_key(&plan, key_name)? {
+            Some(report) if args.json => print_json_report(&report)?,
+            Some(report) => print_summary(&args, &[report]),
+            None => {}
+        }
+        return Ok(());
+    }
+
+    let mut reports = Vec::new();
+    let mut failed = Vec::new();
+    for key_name in &key_names {
+        log_info(&format!("==> {}", key_name).bold().to_string());
+        match setup_and_deliver_key(&plan, key_name) {
+            Ok(report) => reports.extend(report),
+            Err(err) => {
+                log_error(&format!("Error setting up {}: {}", key_name, err));
+                failed.push(key_name.clone());
+            }
+        }
+    }
+    if args.json {
+        print_json_report(&reports)?;
+    } else {
+        print_summary(&args, &reports);
+    }
+
+    if !failed.is_empty() {
+        let succeeded = key_names
+            .iter()
+            .filter(|name| !failed.contains(name))
+            .cloned()
+            .collect();
+        return Err(AppError::KeysFailed {
+            failed,
+            succeeded,
+            total: key_names.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Settings for generating one key with [`setup_key`].
+///
+/// Start from [`KeyConfig::new`] and change the fields that differ from its
+/// defaults.
+#[derive(Debug, Clone)]
+pub struct KeyConfig {
+    /// The key name, used for the key file names.
+    pub name: String,
+    /// The SSH directory for the key files and `authorized_keys`.
+    pub ssh_dir: PathBuf,
+    /// A subdirectory of the SSH directory to put the key files in instead,
+    /// such as `deploy-keys/prod`.
+    pub subdir: Option<PathBuf>,
+    /// Whether to create the SSH directory and subdirectory if they don't
+    /// exist, rather than failing.
+    pub create_dirs: bool,
+    /// The type of key to generate.
+    pub key_type: KeyType,
+    /// The key size in bits, or `None` for the key type's default.
+    pub bits: Option<u32>,
+    /// The key comment, or `None` to use the key name.
+    pub comment: Option<String>,
+    /// The passphrase for the private key, empty for none.
+    pub passphrase: String,
+    /// The number of KDF rounds to protect the passphrase with, or `None`
+    /// for `ssh-keygen`'s default. Ignored without a passphrase.
+    pub kdf_rounds: Option<u32>,
+    /// The file format of the private key.
+    pub format: KeyFormat,
+    /// Whether to add the public key to `authorized_keys`.
+    pub authorize: bool,
+    /// The `authorized_keys` options to restrict the key with, such as
+    /// `no-pty`, if any.
+    pub options: Option<String>,
+    /// The `authorized_keys` file to add the key to, or `None` for the one
+    /// in the SSH directory.
+    pub authorized_keys: Option<PathBuf>,
+    /// Whether to back up `authorized_keys` before modifying it.
+    pub backup: bool,
+    /// Whether to write to the file `authorized_keys` links to when it is a
+    /// symbolic link, instead of refusing.
+    pub follow_symlinks: bool,
+    /// Whether to replace every entry in `authorized_keys` with the new key
+    /// instead of adding it. The file is always backed up first.
+    pub replace_authorized_keys: bool,
+    /// Whether to replace an existing key with the same name.
+    pub overwrite: bool,
+    /// Whether to keep an existing key with the same name, type and size
+    /// instead, and only authorize it. A key that differs is still refused
+    /// or replaced according to `overwrite`.
+    pub reuse_existing: bool,
+    /// How many more times to try starting `ssh-keygen` after a transient
+    /// failure.
+    pub retries: u32,
+    /// Whether to generate a weak key, such as 1024-bit RSA, rather than
+    /// refusing to.
+    pub allow_weak: bool,
+}
+
+impl KeyConfig {
+    /// Creates the settings for a default RSA key without a passphrase, which
+    /// is added to `authorized_keys` after backing the file up. An existing
+    /// key with the same name is left alone.
+    ///
+    /// # Arguments
+    /// * `name` - The key name.
+    /// * `ssh_dir` - The SSH directory for the key files and
+    ///   `authorized_keys`.
+    pub fn new(name: impl Into<String>, ssh_dir: impl Into<PathBuf>) -> Self {
+        KeyConfig {
+            name: name.into(),
+            ssh_dir: ssh_dir.into(),
+            subdir: None,
+            create_dirs: true,
+            key_type: KeyType::default(),
+            bits: None,
+            comment: None,
+            passphrase: String::new(),
+            kdf_rounds: None,
+            format: KeyFormat::default(),
+            authorize: true,
+            options: None,
+            authorized_keys: None,
+            backup: true,
+            follow_symlinks: false,
+            replace_authorized_keys: false,
+            overwrite: false,
+            reuse_existing: false,
+            retries: 0,
+            allow_weak: false,
+        }
+    }
+
+    /// Returns the directory for the key files: the SSH directory, or the
+    /// subdirectory in it.
+    pub fn key_dir(&self) -> PathBuf {
+        match &self.subdir {
+            Some(subdir) => self.ssh_dir.join(subdir),
+            None => self.ssh_dir.clone(),
+        }
+    }
+
+    /// Returns the path of the private key, named after the key.
+    pub fn private_key_path(&self) -> PathBuf {
+        self.key_dir().join(&self.name)
+    }
+
+    /// Returns the path of the public key, next to the private key.
+    pub fn public_key_path(&self) -> PathBuf {
+        self.key_dir().join(format!("{}.pub", self.name))
+    }
+
+    /// Returns the `authorized_keys` file the key is added to.
+    pub fn authorized_keys_path(&self) -> PathBuf {
+        self.authorized_keys
+            .clone()
+            .unwrap_or_else(|| self.ssh_dir.join(AUTHORIZED_KEYS_FILE))
+    }
+}
+
+/// The outcome of [`setup_key`].
+#[derive(Debug, Clone)]
+pub struct KeySetup {
+    /// The path to the private key.
+    pub private_key_path: PathBuf,
+    /// The path to the public key.
+    pub public_key_path: PathBuf,
+    /// The key's fingerprint, if it could be read.
+    pub fingerprint: Option<String>,
+    /// The key size in bits, if it could be read.
+    pub bits: Option<u32>,
+    /// Whether the public key was newly added to `authorized_keys`.
+    pub authorized_keys_updated: bool,
+    /// Whether an existing key was reused rather than a new one generated.
+    pub reused: bool,
+}
+
+/// Generates a key and authorizes it locally.
+///
+/// This is the core of the tool without its prompts or the delivery of the
+/// private key. It will:
+/// 1. Check the key name, size, strength, format and subdirectory, and
+///    create the SSH directory.
+/// 2. Reuse an existing key that matches when `reuse_existing` is set, or
+///    refuse to replace it unless `overwrite` is set.
+/// 3. Generate the keypair and check that its halves match.
+/// 4. Print its fingerprint and append the public key to `authorized_keys`,
+///    unless `authorize` is off.
+///
+/// # Arguments
+/// * `config` - The settings for the key.
+///
+/// # Returns
+/// A `Result<KeySetup, AppError>` with the key's paths and fingerprint, or
+/// an `InvalidArgument` error if the key already exists.
+pub fn setup_key(config: &KeyConfig) -> Result<KeySetup, AppError> {
+    validate_key_name(&config.name)?;
+    let bits = config.key_type.resolve_bits(config.bits, None)?;
+    config.key_type.check_strength(bits, config.allow_weak)?;
+    config.format.check_key_type(config.key_type)?;
+    if let Some(subdir) = &config.subdir {
+        validate_subdir(subdir)?;
+    }
+    ensure_ssh_directory_exists(
+        &config.ssh_dir,
+        config.subdir.as_deref(),
+        config.create_dirs,
+        false,
+    )?;
+
+    let private_key_path = config.private_key_path();
+    let public_key_path = config.public_key_path();
+
+    let reused = config.reuse_existing
+        && existing_key_matches(
+            &private_key_path,
+            &public_key_path,
+            config.key_type,
+            bits,
+            &config.passphrase,
+        );
+    if reused {
+        log_info(&format!(
+            "Reusing the existing key {}.",
+            private_key_path.display()
+        ));
+    } else {
+        // Remove an existing key up front, so `ssh-keygen` never asks about
+        // it.
+        let existing: Vec<&Path> = [private_key_path.as_path(), public_key_path.as_path()]
+            .into_iter()
+            .filter(|path| path.exists())
+            .collect();
+        if let (Some(path), false) = (existing.first(), config.overwrite) {
+            return Err(invalid_argument(format!(
+                "{} already exists",
+                path.display()
+            )));
+        }
+        for path in existing {
+            std::fs::remove_file(path)?;
+        }
+
+        // Generate SSH keypair with the given name using `ssh-keygen` command.
+        run_ssh_keygen(config, bits, false)?;
+
+        // Never install a public key that can't be used with the private
+        // key, e.g. after a partial write.
+        verify_key_pair(&private_key_path, &public_key_path, &config.passphrase)?;
+    }
+
+    // Show the fingerprint so the user can tell which key was created. This
+    // is informational only, so a failure doesn't abort the run.
+    let (fingerprint, bits) = match key_fingerprint(&public_key_path) {
+        Ok(fingerprint) => {
+            log_info(&format!(
+                "Key fingerprint: {} ({} bits)",
+                fingerprint.hash, fingerprint.bits
+            ));
+            (Some(fingerprint.hash), Some(fingerprint.bits))
+        }
+        Err(err) => {
+            log_warning(&format!("could not read the key fingerprint: {}", err));
+            (None, None)
+        }
+    };
+
+    // Append the public key to `authorized_keys` for SSH authentication,
+    // unless the key is meant to be installed elsewhere by hand.
+    let authorized_keys_updated = if !config.authorize {
+        log_info(&format!(
+            "Skipped authorized_keys; the public key for manual installation is at {}",
+            public_key_path.display()
+        ));
+        false
+    } else if config.replace_authorized_keys {
+        replace_authorized_keys(
+            &config.authorized_keys_path(),
+            &public_key_path,
+            config.options.as_deref(),
+            config.follow_symlinks,
+            false,
+        )?
+    } else {
+        append_public_key_to_authorized_keys(
+            &config.authorized_keys_path(),
+            &public_key_path,
+            config.options.as_deref(),
+            config.backup,
+            config.follow_symlinks,
+            false,
+        )?
+    };
+
+    Ok(KeySetup {
+        private_key_path,
+        public_key_path,
+        fingerprint,
+        bits,
+        authorized_keys_updated,
+        reused,
+    })
+}
+
+/// Checks whether an existing key can be reused in place of a new one: both
+/// halves exist and match, it is of the requested type and size, and it
+/// opens with the given passphrase.
+///
+/// # Arguments
+/// * `private_key_path` - The path to the private key.
+/// * `public_key_path` - The path to the public key.
+/// * `key_type` - The requested key type.
+/// * `bits` - The requested key size, if the type has one.
+/// * `passphrase` - The requested passphrase, empty for none.
+///
+/// # Returns
+/// `true` if the key can be reused. Anything that can't be checked counts
+/// as a mismatch.
+fn existing_key_matches(
+    private_key_path: &Path,
+    public_key_path: &Path,
+    key_type: KeyType,
+    bits: Option<u32>,
+    passphrase: &str,
+) -> bool {
+    if !private_key_path.is_file() || !public_key_path.is_file() {
+        return false;
+    }
+    let Ok(fingerprint) = key_fingerprint(public_key_path) else {
+        return false;
+    };
+    // `ssh-keygen -l` names the types in upper case, e.g. `ED25519-SK`.
+    let same_type = fingerprint.key_type.eq_ignore_ascii_case(key_type.as_str());
+    let same_size = bits.is_none_or(|bits| bits == fingerprint.bits);
+    same_type && same_size && verify_key_pair(private_key_path, public_key_path, passphrase).is_ok()
+}
+
+/// Settings shared by every key set up in one run.
+struct SetupPlan<'a> {
+    /// The parsed command-line options.
+    args: &'a Args,
+    /// The SSH directory for the key files and `authorized_keys`.
+    ssh_dir: &'a Path,
+    /// The subdirectory of the SSH directory for the key files, if any.
+    subdir: Option<PathBuf>,
+    /// The key size to pass via `-b`, if any.
+    bits: Option<u32>,
+    /// The passphrase for the private keys, empty for none.
+    passphrase: String,
+    /// The options to restrict the `authorized_keys` entries with, if any.
+    key_options: Option<String>,
+    /// The `authorized_keys` file to add the keys to.
+    authorized_keys_path: PathBuf,
+    /// The workflow to write, for `--emit-workflow`.
+    workflow: Option<WorkflowSettings>,
+    /// The rsync command settings, for `--print-rsync`.
+    rsync: Option<RsyncSettings>,
+    /// The SSH config entry to add, for `--write-ssh-config`.
+    ssh_config: Option<SshConfigSettings>,
+    /// The repository to upload the private keys to, for `--repo`.
+    github: Option<GitHubSecretTarget>,
+    /// The repository's public key, fetched before any key is generated.
+    repo_public_key: Option<RepoPublicKey>,
+    /// The temporary directory to generate the key files in instead, for
+    /// `--ephemeral`.
+    ephemeral_dir: Option<&'a Path>,
+    /// The user to give the files to, for `--owner`.
+    owner: Option<Owner>,
+}
+
+/// Generates one key and carries out every requested step for it.
+///
+/// This will:
+/// 1. Generate the keypair in the SSH directory, after confirming that an
+///    existing key with the same name may be replaced, or moving it aside
+///    for `--rotate`.
+/// 2. Print its fingerprint and append the public key to `authorized_keys`,
+///    then remove a rotated key from it.
+/// 3. Deliver the private key: to a file, the clipboard or a GitHub secret,
+///    or by printing it.
+/// 4. Write the workflow, print the rsync command, add an SSH config entry,
+///    load the key into the agent, install it on a server and test logging
+///    in, as requested.
+///
+/// # Arguments
+/// * `plan` - The settings shared by every key in this run.
+/// * `key_name` - The name of the key to set up.
+///
+/// # Returns
+/// A `Result<Option<SetupReport>, AppError>` with the results for
+/// `--json`, or `None` in dry-run mode.
+fn setup_and_deliver_key(
+    plan: &SetupPlan,
+    key_name: &str,
+) -> Result<Option<SetupReport>, AppError> {
+    let args = plan.args;
+    let ssh_dir = plan.ssh_dir;
+    // The comment is what identifies the key in `authorized_keys`, so it can
+    // be more descriptive than the file name. An explicit comment wins over
+    // the one built from `--comment-host`.
+    let mut comment = match (&args.comment, &args.comment_host) {
+        (Some(comment), _) => comment.clone(),
+        (None, Some(host)) => format!("{}@{}", key_name, host),
+        (None, None) => key_name.to_string(),
+    };
+    if let Some(days) = args.expire_days {
+        let expires = chrono::Local::now().date_naive() + chrono::Days::new(days.into());
+        comment = format!(
+            "{} {}{}",
+            comment,
+            EXPIRY_PREFIX,
+            expires.format(EXPIRY_FORMAT)
+        );
+    }
+    let secret = args
+        .secret_name
+        .clone()
+        .unwrap_or_else(|| secret_name(key_name));
+
+    let config = match plan.ephemeral_dir {
+        Some(dir) => KeyConfig::new(key_name, dir),
+        None => KeyConfig {
+            subdir: plan.subdir.clone(),
+            ..KeyConfig::new(key_name, ssh_dir)
+        },
+    };
+    // Define paths for the private and public key files based on the key name.
+    let private_key_path = config.private_key_path();
+    let public_key_path = config.public_key_path();
+
+    // With `--idempotent`, a key that already matches the request is kept
+    // and delivered again, so repeated runs don't change it.
+    let reuse = args.idempotent
+        && !args.force
+        && existing_key_matches(
+            &private_key_path,
+            &public_key_path,
+            args.key_type(),
+            plan.bits,
+            &plan.passphrase,
+        );
+
+    // Fingerprint the key being rotated while it is still in place, so the
+    // report can show that the new key differs from it.
+    let previous_fingerprint = match (&args.rotate, args.dry_run) {
+        (Some(_), false) => key_fingerprint(&public_key_path)
+            .ok()
+            .map(|fingerprint| fingerprint.hash),
+        _ => None,
+    };
+
+    // Never let `ssh-keygen` clobber a key that may already be in use. A
+    // rotated key is moved aside instead, and stays authorized until its
+    // replacement is.
+    let rotated_public_key = if args.rotate.is_some() {
+        Some(back_up_rotated_key(
+            &private_key_path,
+            &public_key_path,
+            args.dry_run,
+        )?)
+    } else if reuse || confirm_overwrite(args, &private_key_path, &public_key_path)? {
+        None
+    } else {
+        return Err(AppError::Aborted(
+            "the existing key was left untouched".to_string(),
+        ));
+    };
+
+    let config = KeyConfig {
+        key_type: args.key_type(),
+        bits: plan.bits,
+        comment: Some(comment),
+        passphrase: plan.passphrase.clone(),
+        kdf_rounds: args.kdf_rounds,
+        format: args.format,
+        authorize: !args.no_authorize,
+        options: plan.key_options.clone(),
+        authorized_keys: Some(plan.authorized_keys_path.clone()),
+        backup: !args.no_backup,
+        follow_symlinks: args.follow_symlinks,
+        replace_authorized_keys: args.replace_authorized_keys,
+        reuse_existing: reuse,
+        retries: args.retries,
+        allow_weak: args.i_know_its_weak,
+        ..config
+    };
+
+    let output = private_key_output(args, key_name)?;
+
+    if args.dry_run {
+        if reuse {
+            eprintln!(
+                "Would reuse the existing key {}.",
+                private_key_path.display()
+            );
+        } else {
+            generate_ssh_key(&config, true)?;
+        }
+        if args.replace_authorized_keys {
+            replace_authorized_keys(
+                &plan.authorized_keys_path,
+                &public_key_path,
+                plan.key_options.as_deref(),
+                args.follow_symlinks,
+                true,
+            )?;
+        } else if !args.no_authorize {
+            append_public_key_to_authorized_keys(
+                &plan.authorized_keys_path,
+                &public_key_path,
+                plan.key_options.as_deref(),
+                !args.no_backup,
+                args.follow_symlinks,
+                true,
+            )?;
+            if rotated_public_key.is_some() {
+                eprintln!("Would remove the old key from authorized_keys.");
+            }
+        }
+        if let Some(owner) = &plan.owner {
+            eprintln!("Would give the key files to {}.", owner.name);
+        }
+        describe_planned_output(
+            args,
+            output.as_deref(),
+            plan.workflow.as_ref(),
+            plan.github.as_ref(),
+            &secret,
+        );
+        return Ok(None);
+    }
+
+    // Any existing key was confirmed and removed, moved aside, or is reused.
+    let KeySetup {
+        private_key_path,
+        public_key_path,
+        fingerprint,
+        bits,
+        authorized_keys_updated,
+        ..
+    } = track(ProgressStep::Keygen, Some(key_name), || setup_key(&config))?;
+
+    // Only revoke the old key now that the new one is in place. Adding the
+    // new key already backed up the original file.
+    if let (Some(old_public_key), false) = (&rotated_public_key, args.no_authorize) {
+        if remove_public_key_from_authorized_keys(
+            &plan.authorized_keys_path,
+            old_public_key,
+            false,
+            args.follow_symlinks,
+        )? > 0
+        {
+            log_success("Removed the old key from authorized_keys.");
+        }
+    }
+    if let Some(previous) = &previous_fingerprint {
+        log_info(&format!(
+            "Rotated key fingerprint: {} -> {}",
+            previous,
+            fingerprint.as_deref().unwrap_or("unknown")
+        ));
+        if fingerprint.as_ref() == Some(previous) {
+            log_warning("the new key has the same fingerprint as the old one.");
+        }
+    }
+
+    // Read the private key and print it to be added to GitHub Secrets, or
+    // hand it over through a file so it stays out of the terminal.
+    let private_key = SecretString(read_key_file(&private_key_path)?);
+
+    // Some secret stores mangle multi-line values, so optionally encode the
+    // key onto a single line.
+    let private_key = if args.base64 {
+        log_info(
+            "The private key is base64-encoded; decode it (e.g. with `base64 -d`) before use.",
+        );
+        SecretString(BASE64_STANDARD.encode(private_key.expose()))
+    } else {
+        private_key
+    };
+
+    let mut print_private_key = true;
+    if let Some(output) = &output {
+        write_private_file(output, private_key.expose())?;
+        log_success(&format!("Private key written to {}.", output));
+        log_info("Add its contents to GitHub Secrets, then delete the file.");
+        print_private_key = false;
+    }
+    if args.clipboard {
+        match copy_to_clipboard(private_key.expose()) {
+            Ok(()) => print_private_key = false,
+            // Printing stays the fallback only if nothing else has the key.
+            Err(err) => log_warning(&format!("could not copy to the clipboard ({}).", err)),
+        }
+    }
+    if let (Some(target), Some(public_key)) = (&plan.github, &plan.repo_public_key) {
+        let upload = track(ProgressStep::Upload, Some(key_name), || {
+            upload_github_secret(target, public_key, &secret, private_key.expose())
+        });
+        match upload {
+            Ok(()) => {
+                log_success(&format!(
+                    "Private key uploaded to {} as the secret {}.",
+                    target.repo, secret
+                ));
+                print_private_key = false;
+            }
+            Err(err) => log_warning(&format!("{}", err)),
+        }
+    }
+    // Stdout is reserved for the public key.
+    if print_private_key && args.stdout_pubkey {
+        log_info(&format!(
+            "The private key was not printed; it is in {}.",
+            private_key_path.display()
+        ));
+        print_private_key = false;
+    }
+    // Never fall back to printing the key where it could end up in a log.
+    if print_private_key && !may_print_private_key(args) {
+        log_warning(&format!(
+            "the private key was not printed because stdout is not a terminal; it is in {}.",
+            private_key_path.display()
+        ));
+        print_private_key = false;
+    }
+    // The terminal may be shared or recorded, so give the user a chance to
+    // stop the key from appearing on it.
+    if print_private_key && args.is_interactive() && !args.yes && io::stdout().is_terminal() {
+        print_private_key =
+            prompt_yes_no("Print private key now? This will display sensitive material")?;
+        if !print_private_key {
+            log_info(&format!(
+                "The private key was not printed; it is in {}.",
+                private_key_path.display()
+            ));
+        }
+    }
+    if print_private_key && !args.json {
+        print_private_key_block(key_name, args.ci.unwrap_or_default(), &private_key);
+    }
+    // Only `--json` still needs the key; otherwise wipe it now rather than
+    // after the remaining steps.
+    let private_key = (print_private_key && args.json).then_some(private_key);
+    if args.stdout_pubkey {
+        println!("{}", read_key_file(&public_key_path)?.trim_end());
+    }
+
+    // The server side of the setup needs the public key, with the same
+    // restrictions as the local entry.
+    let public_key = if args.show_public_key {
+        let public_key = read_key_file(&public_key_path)?;
+        let entry = match &plan.key_options {
+            Some(options) => format!("{} {}", options, public_key.trim_end()),
+            None => public_key.trim_end().to_string(),
+        };
+        if !args.json {
+            print_public_key_block(&entry);
+        }
+        Some(entry)
+    } else {
+        None
+    };
+
+    let ci = args.ci.unwrap_or_default();
+    if let Some(workflow) = &plan.workflow {
+        track(ProgressStep::Workflow, Some(key_name), || {
+            emit_workflow(workflow, ci, &secret)
+        })?;
+    }
+    // An uploaded key is already where the pipeline reads it from.
+    if (plan.workflow.is_some() || args.ci.is_some()) && plan.github.is_none() {
+        log_info(&ci.secret_instructions(&secret, &private_key_path));
+    }
+    if let (Some(ci), None, false) = (args.ci, &plan.workflow, args.json) {
+        let placeholder = |value: &Option<String>, name: &str| {
+            value.clone().unwrap_or_else(|| format!("<{}>", name))
+        };
+        log_info("Pipeline to deploy with this key:");
+        let pipeline = ci.pipeline(
+            &placeholder(&args.host, "host"),
+            &placeholder(&args.user, "user"),
+            &placeholder(&args.remote_path, "remote path"),
+            &secret,
+        );
+        log_info(pipeline.trim_end());
+    }
+
+    let rsync_command = plan
+        .rsync
+        .as_ref()
+        .filter(|_| args.print_rsync)
+        .map(|rsync| rsync_command(rsync, &private_key_path));
+    if let (Some(command), false) = (&rsync_command, args.json) {
+        log_info("rsync command to deploy with this key:");
+        println!("{}", command);
+    }
+    let action_inputs = plan
+        .rsync
+        .as_ref()
+        .filter(|_| args.print_action_inputs)
+        .map(|rsync| action_inputs(rsync, &secret));
+    if let (Some(inputs), false) = (&action_inputs, args.json) {
+        log_info("Inputs for the rsync deployment step in your workflow:");
+        print!("{}", inputs);
+    }
+
+    let ssh_config_written = match &plan.ssh_config {
+        Some(ssh_config) => track(ProgressStep::SshConfig, Some(key_name), || {
+            write_ssh_config(ssh_dir, ssh_config, &private_key_path)
+        })?,
+        None => false,
+    };
+
+    if args.add_to_agent {
+        // Failing to add the key only warns, so this step always finishes.
+        report_progress(ProgressStep::Agent, "started", Some(key_name));
+        add_to_agent(&private_key_path);
+        report_progress(ProgressStep::Agent, "done", Some(key_name));
+    }
+
+    if let Some(destination) = &args.install_on {
+        track(ProgressStep::Install, Some(key_name), || {
+            install_on_remote(&public_key_path, destination, args.password_auth)
+        })?;
+    }
+
+    if let Some(destination) = &args.test_connection {
+        track(ProgressStep::TestConnection, Some(key_name), || {
+            test_connection(&private_key_path, destination)
+        })?;
+    }
+
+    if let Some(owner) = &plan.owner {
+        let mut paths = vec![ssh_dir.to_path_buf()];
+        for component in plan.subdir.iter().flat_map(|subdir| subdir.components()) {
+            let parent = paths[paths.len() - 1].clone();
+            paths.push(parent.join(component));
+        }
+        paths.extend([private_key_path.clone(), public_key_path.clone()]);
+        if !args.no_authorize {
+            paths.push(plan.authorized_keys_path.clone());
+        }
+        if ssh_config_written {
+            paths.push(ssh_dir.join(SSH_CONFIG_FILE));
+        }
+        for path in &paths {
+            change_owner(path, owner)?;
+        }
+        log_success(&format!("Gave the key files to {}.", owner.name));
+    }
+
+    if let Some(log_file) = &args.log_file {
+        let mut modified = vec![private_key_path.clone(), public_key_path.clone()];
+        if authorized_keys_updated || rotated_public_key.is_some() {
+            modified.push(plan.authorized_keys_path.clone());
+        }
+        if let Some(output) = &output {
+            modified.push(resolve_home_path(output)?);
+        }
+        if let Some(workflow) = &plan.workflow {
+            modified.push(resolve_home_path(&workflow.path)?);
+        }
+        if ssh_config_written {
+            modified.push(ssh_dir.join(SSH_CONFIG_FILE));
+        }
+        append_to_log_file(
+            log_file,
+            key_name,
+            args.key_type(),
+            bits,
+            fingerprint.as_deref(),
+            &modified,
+        )?;
+    }
+
+    if let Some(manifest) = &args.manifest {
+        let entry = ManifestEntry {
+            repo: args.manifest_repo.as_deref().or(args.repo.as_deref()),
+            key: key_name,
+            fingerprint: fingerprint.as_deref(),
+            created: chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+        };
+        append_to_manifest(manifest, args.manifest_format, &entry)?;
+    }
+
+    Ok(Some(SetupReport {
+        key_name: key_name.to_string(),
+        key_type: args.key_type().to_string(),
+        bits,
+        private_key_path,
+        public_key_path,
+        fingerprint,
+        previous_fingerprint,
+        authorized_keys_updated,
+        private_key,
+        public_key,
+        rsync_command,
+        action_inputs,
+    }))
+}
+
+/// Opens the `--log-file` or `--manifest` for appending, creating it if
+/// needed.
+///
+/// # Arguments
+/// * `path` - The file. A leading `~` is expanded.
+///
+/// # Returns
+/// An `io::Result<std::fs::File>` with the open file.
+fn open_log_file(path: &str) -> io::Result<std::fs::File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(resolve_home_path(path)?)
+}
+
+/// Appends a line recording a generated key to the `--log-file`, as an audit
+/// trail of deploy key provisioning. Only the key's public details and the
+/// paths of the files that were changed are written, never the private key.
+///
+/// A line looks like `2025-06-01T12:00:00+00:00 generated key=deploy
+/// type=ed25519 bits=256 fingerprint=SHA256:... modified=~/.ssh/deploy,...`,
+/// all on one line.
+///
+/// # Arguments
+/// * `path` - The log file. A leading `~` is expanded.
+/// * `key_name` - The name of the key.
+/// * `key_type` - The type of the key.
+/// * `bits` - The key size, if it could be read.
+/// * `fingerprint` - The key's fingerprint, if it could be read.
+/// * `modified` - The files that were created or changed.
+///
+/// # Returns
+/// An `io::Result<()>` indicating success or failure.
+fn append_to_log_file(
+    path: &str,
+    key_name: &str,
+    key_type: KeyType,
+    bits: Option<u32>,
+    fingerprint: Option<&str>,
+    modified: &[PathBuf],
+) -> io::Result<()> {
+    let unknown = || "unknown".to_string();
+    let modified: Vec<String> = modified
+        .iter()
+        .map(|path| shell_quote(&path.to_string_lossy()))
+        .collect();
+    let line = format!(
+        "{} generated key={} type={} bits={} fingerprint={} modified={}\n",
+        chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+        key_name,
+        key_type,
+        bits.map_or_else(unknown, |bits| bits.to_string()),
+        fingerprint.map_or_else(unknown, str::to_string),
+        modified.join(",")
+    );
+    open_log_file(path)?.write_all(line.as_bytes())
+}
+
+/// A line of the `--manifest`: which repository a deploy key serves. Only
+/// public details are recorded, never the private key.
+#[derive(Serialize)]
+struct ManifestEntry<'a> {
+    /// The repository, as `owner/name`, if known.
+    repo: Option<&'a str>,
+    /// The name of the key.
+    key: &'a str,
+    /// The key's fingerprint, if it could be read.
+    fingerprint: Option<&'a str>,
+    /// When the key was generated, in RFC 3339 format.
+    created: String,
+}
+
+/// The header line of a CSV `--manifest`, matching [`ManifestEntry`].
+const MANIFEST_CSV_HEADER: &str = "repo,key,fingerprint,created\n";
+
+/// Appends a line for a generated key to the `--manifest`, starting a new
+/// CSV file with a header line.
+///
+/// The file is locked while the line is written, so that runs appending to
+/// a shared manifest at the same time can't interleave their lines or both
+/// write the header.
+///
+/// # Arguments
+/// * `path` - The manifest file. A leading `~` is expanded.
+/// * `format` - The format of the manifest.
+/// * `entry` - The line to append.
+///
+/// # Returns
+/// An `io::Result<()>` indicating success or failure.
+fn append_to_manifest(path: &str, format: ManifestFormat, entry: &ManifestEntry) -> io::Result<()> {
+    let mut line = match format {
+        ManifestFormat::Jsonl => serde_json::to_string(entry).map_err(io::Error::other)?,
+        ManifestFormat::Csv => [
+            entry.repo.unwrap_or(""),
+            entry.key,
+            entry.fingerprint.unwrap_or(""),
+            &entry.created,
+        ]
+        .map(csv_field)
+        .join(","),
+    };
+    line.push('\n');
+
+    let mut file = open_log_file(path)?;
+    // Released when the file is closed.
+    file.lock()?;
+    if format == ManifestFormat::Csv && file.metadata()?.len() == 0 {
+        file.write_all(MANIFEST_CSV_HEADER.as_bytes())?;
+    }
+    file.write_all(line.as_bytes())
+}
+
+/// Quotes a CSV field if it contains a comma, quote or line break, doubling
+/// any quotes inside it.
+///
+/// # Arguments
+/// * `field` - The field value.
+///
+/// # Returns
+/// The field as it should appear in the CSV line.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Prints the results as JSON, for `--json`.
+///
+/// # Arguments
+/// * `report` - A single [`SetupReport`], or a list of them when several
+///   keys were set up.
+///
+/// # Returns
+/// An `io::Result<()>` indicating success or failure.
+fn print_json_report(report: &impl Serialize) -> io::Result<()> {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(report).map_err(io::Error::other)?
+    );
+    Ok(())
+}
+
+/// The results of setting up one key, printed as JSON by `--json`.
+#[derive(Serialize)]
+struct SetupReport {
+    /// The name of the generated key.
+    key_name: String,
+    /// The type of the generated key.
+    key_type: String,
+    /// The key size in bits, if it could be read.
+    bits: Option<u32>,
+    /// The expanded path to the private key.
+    private_key_path: PathBuf,
+    /// The expanded path to the public key.
+    public_key_path: PathBuf,
+    /// The key's fingerprint, if it could be read.
+    fingerprint: Option<String>,
+    /// The fingerprint of the key it replaced, for `--rotate`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_fingerprint: Option<String>,
+    /// Whether the public key was newly added to `authorized_keys`.
+    authorized_keys_updated: bool,
+    /// The private key, unless it was delivered by `--output`,
+    /// `--clipboard` or `--repo` instead, or may not be printed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private_key: Option<SecretString>,
+    /// The `authorized_keys` line for the server, for `--show-public-key`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_key: Option<String>,
+    /// The rsync command, for `--print-rsync`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rsync_command: Option<String>,
+    /// The action step's `with:` block, for `--print-action-inputs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action_inputs: Option<String>,
+}
+
+/// Prints a box summing up each key that was set up, so the outcome can be
+/// checked at a glance after the step-by-step messages. Nothing is printed
+/// with `--quiet`.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+/// * `reports` - The results for each key that was set up.
+fn print_summary(args: &Args, reports: &[SetupReport]) {
+    if is_quiet() || reports.is_empty() {
+        return;
+    }
+
+    let mut lines = Vec::new();
+    for report in reports {
+        if !lines.is_empty() {
+            lines.push(String::new());
+        }
+        lines.push(report.key_name.clone());
+        let size = report
+            .bits
+            .map(|bits| format!(", {} bits", bits))
+            .unwrap_or_default();
+        lines.push(format!("  Type:            {}{}", report.key_type, size));
+        lines.push(format!(
+            "  Private key:     {}",
+            report.private_key_path.display()
+        ));
+        lines.push(format!(
+            "  Public key:      {}",
+            report.public_key_path.display()
+        ));
+        let authorized_keys = if args.no_authorize {
+            "skipped"
+        } else if report.authorized_keys_updated {
+            "authorized"
+        } else {
+            "already authorized"
+        };
+        lines.push(format!("  authorized_keys: {}", authorized_keys));
+        lines.push(format!(
+            "  Fingerprint:     {}",
+            report.fingerprint.as_deref().unwrap_or("unknown")
+        ));
+    }
+
+    let width = lines
+        .iter()
+        .map(|line| line.chars().count())
+        .max()
+        .unwrap_or(0);
+    let border = format!("+{}+", "-".repeat(width + 2));
+    eprintln!();
+    eprintln!("{}", "Summary".bold());
+    eprintln!("{}", border);
+    for line in &lines {
+        eprintln!("| {:<width$} |", line);
+    }
+    eprintln!("{}", border);
+}
+
+/// Prints where the private key and workflow would go, for `--dry-run`.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+/// * `output` - The file to write the private key to, if any.
+/// * `workflow` - The workflow settings, if a workflow was requested.
+/// * `github` - The repository to upload to, if an upload was requested.
+/// * `secret` - The name of the secret to upload to.
+fn describe_planned_output(
+    args: &Args,
+    output: Option<&str>,
+    workflow: Option<&WorkflowSettings>,
+    github: Option<&GitHubSecretTarget>,
+    secret: &str,
+) {
+    if let Some(output) = output {
+        eprintln!("Would write the private key to {}.", output);
+    }
+    if args.clipboard {
+        eprintln!("Would copy the private key to the clipboard.");
+    }
+    if let Some(target) = github {
+        eprintln!(
+            "Would upload the private key to {} as the secret {}.",
+            target.repo, secret
+        );
+    }
+    if output.is_none() && !args.clipboard && github.is_none() {
+        eprintln!("Would print the private key.");
+    }
+    if let Some(workflow) = workflow {
+        eprintln!("Would write a workflow to {}.", workflow.path);
+    }
+    if let (true, Some(alias)) = (args.write_ssh_config, &args.alias) {
+        eprintln!("Would add a Host {} entry to the SSH config file.", alias);
+    }
+    if args.add_to_agent {
+        eprintln!("Would add the key to ssh-agent.");
+    }
+    if let Some(host) = &args.add_known_host {
+        eprintln!(
+            "Would scan the host keys of {} and add them to known_hosts.",
+            host
+        );
+    }
+    if let Some(destination) = &args.install_on {
+        eprintln!("Would install the public key on {}.", destination);
+    }
+    if let Some(destination) = &args.test_connection {
+        eprintln!("Would test logging in to {} with the key.", destination);
+    }
+}
+
+/// Returns whether the private key may be printed to stdout.
+///
+/// CI logs are often archived or public, so the key is only printed to a
+/// terminal unless `--print-private-key` asks for it explicitly.
+fn may_print_private_key(args: &Args) -> bool {
+    args.print_private_key || io::stdout().is_terminal()
+}
+
+/// Enables colored output only when stdout and stderr are terminals and
+/// `NO_COLOR` is not set, so CI logs and redirected output stay free of
+/// escape codes.
+pub fn configure_colors() {
+    let enabled = io::stdout().is_terminal()
+        && io::stderr().is_terminal()
+        && env::var_os("NO_COLOR").is_none();
+    colored::control::set_override(enabled);
+}
+
+/// Returns whether informational output is suppressed by `--quiet`.
+fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Prints an informational message to stderr, unless `--quiet` is set.
+///
+/// Only results, such as the private key or `--json` output, go to stdout,
+/// so that `sshcode > key.pem` captures nothing but the key. Progress,
+/// prompts, warnings and `--dry-run` plans all go to stderr.
+fn log_info(message: &str) {
+    if !is_quiet() {
+        eprintln!("{}", message);
+    }
+}
+
+/// Prints a message reporting a completed step to stderr, in green, unless
+/// `--quiet` is set.
+fn log_success(message: &str) {
+    if !is_quiet() {
+        eprintln!("{}", message.green());
+    }
+}
+
+/// Prints a warning to stderr, in yellow, unless `--quiet` is set.
+fn log_warning(message: &str) {
+    if !is_quiet() {
+        eprintln!("{} {}", "Warning:".yellow().bold(), message.yellow());
+    }
+}
+
+/// Prints a debugging message to stderr when `--verbose` is set, keeping
+/// stdout clean for the private key.
+fn log_verbose(message: &str) {
+    if VERBOSE.load(Ordering::Relaxed) {
+        eprintln!("{}", redact(message).dimmed());
+    }
+}
+
+/// Prints an error to stderr, in red. Errors are shown even with `--quiet`.
+fn log_error(message: &str) {
+    eprintln!("{}", redact(message).red());
+}
+
+/// The marker starting a private key in PEM or OpenSSH format. The key type,
+/// if any, sits between this and [`PRIVATE_KEY_END`].
+const PRIVATE_KEY_BEGIN: &str = "-----BEGIN ";
+
+/// The end of the line that starts or ends a private key.
+const PRIVATE_KEY_END: &str = "PRIVATE KEY-----";
+
+/// Replaces every private key found in `text` with `***`, so that a log line
+/// or error message can never carry one, even if it captured the contents of
+/// a key file. A key without an end marker is masked to the end of the text.
+///
+/// # Arguments
+/// * `text` - The message to print.
+///
+/// # Returns
+/// The message with any private keys masked.
+fn redact(text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = find_private_key_marker(rest, PRIVATE_KEY_BEGIN) {
+        redacted.push_str(&rest[..start]);
+        redacted.push_str("***");
+        let after_begin = &rest[start + PRIVATE_KEY_BEGIN.len()..];
+        let Some(header_end) = after_begin.find(PRIVATE_KEY_END) else {
+            return redacted;
+        };
+        let body = &after_begin[header_end + PRIVATE_KEY_END.len()..];
+        rest = match find_private_key_marker(body, "-----END ") {
+            Some(end) => {
+                let footer = &body[end..];
+                match footer.find(PRIVATE_KEY_END) {
+                    Some(footer_end) => &footer[footer_end + PRIVATE_KEY_END.len()..],
+                    None => return redacted,
+                }
+            }
+            None => return redacted,
+        };
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// Finds the next `marker` that starts a private key line, i.e. one
+/// followed by `PRIVATE KEY-----` on the same line, so certificates and
+/// public keys are left alone.
+fn find_private_key_marker(text: &str, marker: &str) -> Option<usize> {
+    let mut offset = 0;
+    while let Some(found) = text[offset..].find(marker) {
+        let start = offset + found;
+        let line = text[start..].lines().next().unwrap_or_default();
+        if line.contains(PRIVATE_KEY_END) {
+            return Some(start);
+        }
+        offset = start + marker.len();
+    }
+    None
+}
+
+/// A private key held in memory. Formatting it with `{}` or `{:?}` prints
+/// `***`, so it can't slip into a log line or error message by accident;
+/// the places that deliver the key call [`SecretString::expose`] instead.
+/// The memory is wiped when it is dropped, so the key doesn't linger on the
+/// heap after it has been delivered.
+struct SecretString(String);
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl SecretString {
+    /// Returns the secret itself, for the places that are meant to print,
+    /// write or upload it.
+    fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "***")
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretString(***)")
+    }
+}
+
+/// `--json` is one of the places meant to print the key, so it is
+/// serialized as-is.
+impl Serialize for SecretString {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.expose())
+    }
+}
+
+/// Prints the private key to stdout between clearly visible markers,
+/// labelled with the key name and where it goes, so it can be selected
+/// reliably. The markers go to stderr, and are left out with `--quiet`, so
+/// redirected output is just the key.
+fn print_private_key_block(key_name: &str, ci: CiProvider, private_key: &SecretString) {
+    if !is_quiet() {
+        eprintln!(
+            "{}",
+            format!(
+                "==== Private key '{}' to add to {} (copy the lines between the markers) ====",
+                key_name,
+                ci.secret_store()
+            )
+            .cyan()
+            .bold()
+        );
+    }
+    println!("{}", private_key.expose().trim_end());
+    if !is_quiet() {
+        eprintln!("{}", "==== End of private key ====".cyan().bold());
+    }
+}
+
+/// Prints the `authorized_keys` line for the public key to stdout between
+/// markers, with a note on where it goes. Like the private key's, the markers
+/// go to stderr and are left out with `--quiet`.
+fn print_public_key_block(entry: &str) {
+    if !is_quiet() {
+        eprintln!(
+            "{}",
+            "==== Public key: add this line to the server's ~/.ssh/authorized_keys ===="
+                .cyan()
+                .bold()
+        );
+    }
+    println!("{}", entry);
+    if !is_quiet() {
+        eprintln!("{}", "==== End of public key ====".cyan().bold());
+    }
+}
+
+/// Determines the SSH key names from the arguments or an interactive prompt.
+///
+/// Names given via `--name` or `SSH_KEY_NAME` are used as-is, and there may
+/// be several. Otherwise a single name is prompted for when stdin is a
+/// terminal and `--non-interactive` isn't set; if not, the default name is
+/// used without reading stdin. Every name is checked with
+/// [`validate_key_name`].
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+///
+/// # Returns
+/// A `Result<Vec<String>, AppError>` with the trimmed key names, or an
+/// `InvalidKeyName` error if a name is unsafe to use as a file name or
+/// given twice.
+fn resolve_key_names(args: &Args) -> Result<Vec<String>, AppError> {
+    // `SSH_KEY_NAME` may well be set for other runs, so `--rotate` simply
+    // takes precedence over it.
+    if let Some(name) = &args.rotate {
+        let name = name.trim();
+        validate_key_name(name)?;
+        return Ok(vec![name.to_string()]);
+    }
+
+    // Likewise, `--batch` replaces the names rather than adding to them.
+    if let (Some(count), Some(prefix)) = (args.batch, &args.prefix) {
+        let prefix = prefix.trim();
+        return (1..=count)
+            .map(|index| {
+                let name = format!("{}-{}", prefix, index);
+                validate_key_name(&name)?;
+                Ok(name)
+            })
+            .collect();
+    }
+
+    if args.name.is_empty() {
+        return Ok(vec![prompt_key_name(args)?]);
+    }
+
+    let mut names: Vec<String> = Vec::new();
+    for name in &args.name {
+        // Unlike an empty answer at the prompt, an explicitly empty name
+        // is a mistake rather than a request for the default.
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(AppError::InvalidKeyName(
+                "--name (or SSH_KEY_NAME) must not be empty".to_string(),
+            ));
+        }
+        validate_key_name(name)?;
+        if names.iter().any(|existing| existing == name) {
+            return Err(AppError::InvalidKeyName(format!(
+                "'{}' is given more than once",
+                name
+            )));
+        }
+        names.push(name.to_string());
+    }
+
+    Ok(names)
+}
+
+/// Makes sure a key name that is one of `ssh`'s default key file names,
+/// such as `id_ed25519`, is meant: replacing that key could lock the user
+/// out of every server it is authorized on.
+///
+/// The user is warned and asked to confirm, or `--force` is required in
+/// non-interactive mode. Dry runs only warn.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+/// * `key_names` - The key names to check.
+///
+/// # Returns
+/// A `Result<(), AppError>` that is an `InvalidArgument` error in
+/// non-interactive mode without `--force`, or an `Aborted` error if the user
+/// declines.
+fn confirm_well_known_names(args: &Args, key_names: &[String]) -> Result<(), AppError> {
+    for name in key_names {
+        if !WELL_KNOWN_KEY_NAMES.contains(&name.as_str()) {
+            continue;
+        }
+
+        log_warning(&format!(
+            "'{}' is a default SSH key name, usually for your personal key, which ssh \
+             offers to every server; replacing it can lock you out of them all.",
+            name
+        ));
+        if args.dry_run || args.force {
+            continue;
+        }
+        if !args.is_interactive() {
+            return Err(invalid_argument(format!(
+                "refusing to use the default key name '{}' without --force; choose a \
+                 different name for the deploy key",
+                name
+            )));
+        }
+        if !prompt_yes_no(&format!("Use '{}' anyway?", name))? {
+            return Err(AppError::Aborted(format!(
+                "choose a different name than '{}'",
+                name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Asks for a key name, falling back to the default for an empty answer or
+/// when prompting isn't possible. Pressing Enter on an empty line picks the
+/// default, but a closed stdin (Ctrl-D) is taken as giving up rather than as
+/// an answer.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+///
+/// # Returns
+/// A `Result<String, AppError>` with the trimmed key name, an
+/// `InvalidKeyName` error if the name is unsafe to use as a file name, or an
+/// `Aborted` error if stdin is closed before a name is entered.
+fn prompt_key_name(args: &Args) -> Result<String, AppError> {
+    let default_name = args.default_key_name();
+    validate_key_name(default_name)?;
+    if !args.is_interactive() {
+        return Ok(default_name.to_string());
+    }
+
+    // Prompt user to enter the SSH key name.
+    eprintln!(
+        "Enter the name you want to use for the SSH key (default: {}):",
+        default_name
+    );
+    let mut key_name = String::new();
+    if stdin().read_line(&mut key_name)? == 0 {
+        return Err(AppError::Aborted(
+            "stdin was closed before a key name was entered; pass --name, or \
+             --non-interactive to use the default"
+                .to_string(),
+        ));
+    }
+
+    // Remove any whitespace and set a default if the line is empty.
+    let key_name = key_name.trim();
+    if key_name.is_empty() {
+        return Ok(default_name.to_string());
+    }
+
+    validate_key_name(key_name)?;
+    Ok(key_name.to_string())
+}
+
+/// The key types offered by [`prompt_key_type`], in menu order. The first
+/// is the default.
+const KEY_TYPE_MENU: [(KeyType, &str); 3] = [
+    (KeyType::Ed25519, "ed25519 (recommended)"),
+    (KeyType::Rsa, "rsa, 4096 bits"),
+    (KeyType::Ecdsa, "ecdsa"),
+];
+
+/// Asks which type of key to generate from a numbered menu, for interactive
+/// runs without `--key-type`. An empty answer picks ed25519, and anything
+/// that isn't a menu number is asked again.
+///
+/// # Returns
+/// A `Result<KeyType, AppError>` with the chosen key type, or an `Aborted`
+/// error if stdin is closed before one is chosen.
+fn prompt_key_type() -> Result<KeyType, AppError> {
+    eprintln!("Choose the type of key to generate:");
+    for (number, (_, label)) in KEY_TYPE_MENU.iter().enumerate() {
+        eprintln!("  {}) {}", number + 1, label);
+    }
+
+    loop {
+        eprintln!("Enter a number (default: 1):");
+        let mut answer = String::new();
+        if stdin().read_line(&mut answer)? == 0 {
+            return Err(AppError::Aborted(
+                "stdin was closed before a key type was chosen; pass --key-type".to_string(),
+            ));
+        }
+
+        let answer = answer.trim();
+        if answer.is_empty() {
+            return Ok(KEY_TYPE_MENU[0].0);
+        }
+        match answer.parse::<usize>() {
+            Ok(number) if (1..=KEY_TYPE_MENU.len()).contains(&number) => {
+                return Ok(KEY_TYPE_MENU[number - 1].0)
+            }
+            _ => log_warning(&format!("'{}' is not one of the choices.", answer)),
+        }
+    }
+}
+
+/// Rejects options that can only apply to one key when several key names
+/// are given, such as a single output file.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+///
+/// # Returns
+/// A `Result<(), AppError>` with an `InvalidArgument` error naming the
+/// first such option.
+fn ensure_single_key_options(args: &Args) -> Result<(), AppError> {
+    let single_key_options = [
+        ("--output", args.output.is_some()),
+        ("--clipboard", args.clipboard),
+        ("--emit-workflow", args.emit_workflow.is_some()),
+        ("--secret-name", args.secret_name.is_some()),
+        ("--comment", args.comment.is_some()),
+        ("--write-ssh-config", args.write_ssh_config),
+        ("--replace-authorized-keys", args.replace_authorized_keys),
+    ];
+    for (flag, given) in single_key_options {
+        if given {
+            return Err(invalid_argument(format!(
+                "{} can't be used with more than one key name",
+                flag
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that a key name is safe to use as a file name inside the SSH
+/// directory.
+///
+/// The name is interpolated into the key file paths, so anything that could
+/// escape the directory or produce a hidden or unusable file is rejected:
+/// path separators, `..`, NUL and other control characters, a leading dot,
+/// an empty name, and names longer than `MAX_KEY_NAME_LEN`.
+///
+/// # Arguments
+/// * `key_name` - The trimmed key name.
+///
+/// # Returns
+/// A `Result<(), AppError>` with an `InvalidKeyName` error describing the
+/// problem.
+pub fn validate_key_name(key_name: &str) -> Result<(), AppError> {
+    let reason = if key_name.is_empty() {
+        "the name must not be empty".to_string()
+    } else if key_name.len() > MAX_KEY_NAME_LEN {
+        format!(
+            "the name must be at most {} characters long",
+            MAX_KEY_NAME_LEN
+        )
+    } else if key_name.contains(['/', '\\']) {
+        format!("'{}' must not contain path separators", key_name)
+    } else if key_name.contains("..") {
+        format!("'{}' must not contain '..'", key_name)
+    } else if key_name.chars().any(char::is_control) {
+        "the name must not contain NUL or other control characters".to_string()
+    } else if key_name.starts_with('.') {
+        format!("'{}' must not start with a dot", key_name)
+    } else {
+        return Ok(());
+    };
+
+    Err(AppError::InvalidKeyName(reason))
+}
+
+/// Determines the passphrase to protect the private key with.
+///
+/// The passphrase comes from `--passphrase`, or is read twice from the
+/// terminal without echoing when `--ask-passphrase` is set. Without either
+/// flag the key is left unencrypted.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+///
+/// # Returns
+/// A `Result<String, AppError>` with the passphrase, empty for no
+/// passphrase.
+fn resolve_passphrase(args: &Args) -> Result<String, AppError> {
+    if let Some(passphrase) = &args.passphrase {
+        return Ok(passphrase.clone());
+    }
+
+    if !args.ask_passphrase {
+        return Ok(String::new());
+    }
+
+    let passphrase = rpassword::prompt_password("Enter passphrase for the private key: ")?;
+    let confirmation = rpassword::prompt_password("Enter the same passphrase again: ")?;
+    if passphrase != confirmation {
+        return Err(invalid_argument("passphrases do not match".to_string()));
+    }
+
+    Ok(passphrase)
+}
+
+/// Checks whether a key with the chosen name already exists and, if so,
+/// whether it may be replaced.
+///
+/// With `--force` the existing files are replaced without asking. In
+/// non-interactive mode an existing key is never replaced without `--force`.
+/// Otherwise the user is asked for confirmation, or without a prompt this is
+/// an error, since `ssh-keygen` would otherwise hang on its own question.
+/// When replacement is allowed the old files are removed so `ssh-keygen`
+/// doesn't prompt on its own. In dry-run mode nothing is asked or removed.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+/// * `private_key_path` - The path of the private key.
+/// * `public_key_path` - The path of the public key.
+///
+/// # Returns
+/// A `Result<bool, AppError>` that is `true` when generation may proceed, or
+/// an `InvalidArgument` error in non-interactive mode without `--force`.
+fn confirm_overwrite(
+    args: &Args,
+    private_key_path: &Path,
+    public_key_path: &Path,
+) -> Result<bool, AppError> {
+    let existing: Vec<&Path> = [private_key_path, public_key_path]
+        .into_iter()
+        .filter(|path| path.exists())
+        .collect();
+
+    if existing.is_empty() {
+        return Ok(true);
+    }
+
+    for path in &existing {
+        log_warning(&format!("Key file already exists: {}", path.display()));
+    }
+
+    if args.dry_run {
+        eprintln!("Would replace the existing key, with confirmation unless --force is given.");
+        return Ok(true);
+    }
+
+    if !args.force && !args.is_interactive() {
+        return Err(invalid_argument(format!(
+            "{} already exists; pass --force to replace it",
+            existing[0].display()
+        )));
+    }
+
+    let overwrite =
+        confirm_destructive(args, "Overwrite the existing key? This cannot be undone.")?;
+    if overwrite {
+        for path in &existing {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    Ok(overwrite)
+}
+
+/// Moves an existing key aside for `--rotate`, to `<file>.<timestamp>.bak`.
+///
+/// In dry-run mode nothing is moved.
+///
+/// # Arguments
+/// * `private_key_path` - The path of the private key being rotated.
+/// * `public_key_path` - The path of the public key being rotated.
+/// * `dry_run` - Only print what would be moved.
+///
+/// # Returns
+/// A `Result<String, AppError>` with the old public key, so it can be
+/// removed from `authorized_keys`, or an `InvalidArgument` error if there is
+/// no key to rotate.
+fn back_up_rotated_key(
+    private_key_path: &Path,
+    public_key_path: &Path,
+    dry_run: bool,
+) -> Result<String, AppError> {
+    let old_public_key = match std::fs::read_to_string(public_key_path) {
+        Ok(public_key) => public_key,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(invalid_argument(format!(
+                "there is no key to rotate at {}",
+                public_key_path.display()
+            )));
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let timestamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    for path in [private_key_path, public_key_path] {
+        if !path.exists() {
+            continue;
+        }
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let backup_path = path.with_file_name(format!("{}.{}.bak", file_name, timestamp));
+        if dry_run {
+            eprintln!("Would move {} to {}", path.display(), backup_path.display());
+        } else {
+            std::fs::rename(path, &backup_path)?;
+            log_info(&format!(
+                "Backed up {} to {}",
+                path.display(),
+                backup_path.display()
+            ));
+        }
+    }
+
+    Ok(old_public_key)
+}
+
+/// Decides whether a destructive action may go ahead.
+///
+/// `--force` allows it without asking, non-interactive mode refuses it
+/// without `--force`, and otherwise the user is asked.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+/// * `question` - The question to ask, without the `[y/N]` suffix.
+///
+/// # Returns
+/// An `io::Result<bool>` that is `true` when the action may proceed.
+fn confirm_destructive(args: &Args, question: &str) -> io::Result<bool> {
+    if args.force {
+        Ok(true)
+    } else if !args.is_interactive() {
+        log_warning("Refusing to continue in non-interactive mode without --force.");
+        Ok(false)
+    } else {
+        prompt_yes_no(question)
+    }
+}
+
+/// Asks the user a yes/no question on stdin.
+///
+/// Anything other than `y` or `yes` (case-insensitive) counts as no, and so
+/// does a closed stdin.
+///
+/// # Arguments
+/// * `question` - The question to display, without the `[y/N]` suffix.
+///
+/// # Returns
+/// An `io::Result<bool>` that is `true` when the user answered yes.
+fn prompt_yes_no(question: &str) -> io::Result<bool> {
+    eprintln!("{} [y/N]", question);
+    let mut answer = String::new();
+    if stdin().read_line(&mut answer)? == 0 {
+        log_info("No answer (stdin was closed); taking that as no.");
+        return Ok(false);
+    }
+
+    let answer = answer.trim().to_ascii_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+/// Settings for the GitHub Actions workflow written by `--emit-workflow`.
+struct WorkflowSettings {
+    /// Where to write the workflow file.
+    path: String,
+    /// The deployment server's host name or address.
+    host: String,
+    /// The user to log in as on the deployment server.
+    user: String,
+    /// The directory on the deployment server to deploy into.
+    remote_path: String,
+}
+
+/// Collects the settings for `--emit-workflow`, prompting for any that
+/// weren't given on the command line.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+///
+/// # Returns
+/// A `Result<Option<WorkflowSettings>, AppError>` that is `None` when no
+/// workflow was requested, or an `InvalidArgument` error if a setting is
+/// missing in non-interactive mode.
+fn resolve_workflow_settings(args: &Args) -> Result<Option<WorkflowSettings>, AppError> {
+    let Some(path) = &args.emit_workflow else {
+        return Ok(None);
+    };
+
+    let setting = |value: &Option<String>, flag: &str, question: &str| match value {
+        Some(value) => Ok(value.clone()),
+        None if !args.is_interactive() => Err(invalid_argument(format!(
+            "--emit-workflow requires {} in non-interactive mode",
+            flag
+        ))),
+        None => prompt_value(question),
+    };
+
+    Ok(Some(WorkflowSettings {
+        path: path.clone(),
+        host: setting(&args.host, "--host", "Enter the deployment server host:")?,
+        user: setting(&args.user, "--user", "Enter the user to deploy as:")?,
+        remote_path: setting(
+            &args.remote_path,
+            "--remote-path",
+            "Enter the directory on the server to deploy into:",
+        )?,
+    }))
+}
+
+/// Settings for the rsync command printed by `--print-rsync`, or the action
+/// inputs printed by `--print-action-inputs`.
+struct RsyncSettings {
+    /// The local directory to deploy.
+    local_path: String,
+    /// The deployment server's host name or address.
+    host: String,
+    /// The user to log in as on the deployment server.
+    user: String,
+    /// The directory on the deployment server to deploy into.
+    remote_path: String,
+    /// Whether to delete remote files that don't exist locally.
+    delete: bool,
+}
+
+/// Collects the settings for `--print-rsync` and `--print-action-inputs`.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+///
+/// # Returns
+/// A `Result<Option<RsyncSettings>, AppError>` that is `None` when neither
+/// was requested, or an `InvalidArgument` error if `--host`, `--user` or
+/// `--remote-path` is missing.
+fn resolve_rsync_settings(args: &Args) -> Result<Option<RsyncSettings>, AppError> {
+    let mode = if args.print_rsync {
+        "--print-rsync"
+    } else if args.print_action_inputs {
+        "--print-action-inputs"
+    } else {
+        return Ok(None);
+    };
+
+    let required = |value: &Option<String>, flag: &str| {
+        value
+            .clone()
+            .ok_or_else(|| invalid_argument(format!("{} requires {}", mode, flag)))
+    };
+
+    Ok(Some(RsyncSettings {
+        local_path: args.local_path.clone().unwrap_or_else(|| ".".to_string()),
+        host: required(&args.host, "--host")?,
+        user: required(&args.user, "--user")?,
+        remote_path: required(&args.remote_path, "--remote-path")?,
+        delete: args.delete,
+    }))
+}
+
+/// Settings for the SSH config entry written by `--write-ssh-config`.
+struct SshConfigSettings {
+    /// The name to use with `ssh`, as in `ssh <alias>`.
+    alias: String,
+    /// The deployment server's host name or address.
+    host: String,
+    /// The user to log in as on the deployment server.
+    user: String,
+}
+
+/// Collects the settings for `--write-ssh-config`.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+///
+/// # Returns
+/// A `Result<Option<SshConfigSettings>, AppError>` that is `None` when no
+/// entry was requested, or an `InvalidArgument` error if `--host`, `--user`
+/// or `--alias` is missing, or the alias isn't a single plain name.
+fn resolve_ssh_config_settings(args: &Args) -> Result<Option<SshConfigSettings>, AppError> {
+    if !args.write_ssh_config {
+        return Ok(None);
+    }
+
+    let required = |value: &Option<String>, flag: &str| {
+        value
+            .clone()
+            .ok_or_else(|| invalid_argument(format!("--write-ssh-config requires {}", flag)))
+    };
+
+    let alias = required(&args.alias, "--alias")?;
+    // A space would make the entry match several names, and wildcards or
+    // negation would make it match hosts other than this one.
+    if alias.is_empty()
+        || alias
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || "*?!,\"".contains(c))
+    {
+        return Err(invalid_argument(format!(
+            "invalid --alias '{}': use a single name without spaces or wildcards",
+            alias
+        )));
+    }
+
+    Ok(Some(SshConfigSettings {
+        alias,
+        host: required(&args.host, "--host")?,
+        user: required(&args.user, "--user")?,
+    }))
+}
+
+/// Appends a `Host` entry for the key to the SSH config file in the SSH
+/// directory, so that `ssh <alias>` logs in to the server with it.
+///
+/// Like [`add_known_host`], nothing is added when the file already has an
+/// entry for the alias, and the existing contents are left as they are.
+///
+/// # Arguments
+/// * `ssh_dir` - The SSH directory containing `config`.
+/// * `ssh_config` - The alias, host and user for the entry.
+/// * `private_key_path` - The path of the private key.
+///
+/// # Returns
+/// An `io::Result<bool>` that is `true` when the entry was added, and
+/// `false` when the alias already had one.
+fn write_ssh_config(
+    ssh_dir: &Path,
+    ssh_config: &SshConfigSettings,
+    private_key_path: &Path,
+) -> io::Result<bool> {
+    let config_path = ssh_dir.join(SSH_CONFIG_FILE);
+    let existing = match std::fs::read_to_string(&config_path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+
+    if existing
+        .lines()
+        .any(|line| ssh_config_host_patterns(line).contains(&ssh_config.alias.as_str()))
+    {
+        log_info(&format!(
+            "{} already has a Host {} entry; leaving it as it is.",
+            config_path.display(),
+            ssh_config.alias
+        ));
+        return Ok(false);
+    }
+
+    // Separate the entry from whatever came before it with a blank line.
+    let mut entry = String::new();
+    if !existing.is_empty() {
+        if !existing.ends_with('\n') {
+            entry.push('\n');
+        }
+        entry.push('\n');
+    }
+    // Only offer this key, so `ssh <alias>` doesn't fail with "too many
+    // authentication failures" when the agent holds several keys.
+    entry.push_str(&format!(
+        "Host {}\n    HostName {}\n    User {}\n    IdentityFile \"{}\"\n    IdentitiesOnly yes\n",
+        ssh_config.alias,
+        ssh_config.host,
+        ssh_config.user,
+        private_key_path.display()
+    ));
+
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(&config_path)?.write_all(entry.as_bytes())?;
+    log_success(&format!(
+        "Added a Host {} entry to {}; connect with `ssh {}`.",
+        ssh_config.alias,
+        config_path.display(),
+        ssh_config.alias
+    ));
+
+    Ok(true)
+}
+
+/// Returns the host patterns of an SSH config line if it is a `Host` line,
+/// such as `Host prod staging` or `Host=prod`, and nothing otherwise.
+fn ssh_config_host_patterns(line: &str) -> Vec<&str> {
+    let line = line.trim_start();
+    let Some(keyword_end) = line.find(|c: char| c.is_whitespace() || c == '=') else {
+        return Vec::new();
+    };
+    if !line[..keyword_end].eq_ignore_ascii_case("host") {
+        return Vec::new();
+    }
+
+    line[keyword_end..]
+        .trim_start_matches(|c: char| c.is_whitespace() || c == '=')
+        .split_whitespace()
+        .map(|pattern| pattern.trim_matches('"'))
+        .collect()
+}
+
+/// Builds an rsync command line that deploys over SSH with the given key.
+///
+/// The local path gets a trailing slash so its contents, rather than the
+/// directory itself, are copied. Every path is quoted for the shell, and the
+/// key path is quoted again inside the `-e` command, which rsync splits
+/// itself.
+///
+/// # Arguments
+/// * `rsync` - The rsync settings.
+/// * `private_key_path` - The path to the private key.
+fn rsync_command(rsync: &RsyncSettings, private_key_path: &Path) -> String {
+    let ssh = format!(
+        "ssh -i {}",
+        shell_quote(&private_key_path.to_string_lossy())
+    );
+
+    let mut local_path = rsync.local_path.clone();
+    if !local_path.ends_with('/') {
+        local_path.push('/');
+    }
+    let destination = format!("{}@{}:{}", rsync.user, rsync.host, rsync.remote_path);
+
+    let mut parts = vec!["rsync".to_string(), "-avz".to_string()];
+    if rsync.delete {
+        parts.push("--delete".to_string());
+    }
+    parts.push("-e".to_string());
+    parts.push(shell_quote(&ssh));
+    parts.push(shell_quote(&local_path));
+    parts.push(shell_quote(&destination));
+    parts.join(" ")
+}
+
+/// Builds the `with:` block for an rsync deployment action step, such as
+/// `burnett01/rsync-deployments`, that deploys with the key.
+///
+/// The private key is read from the repository secret rather than pasted
+/// in, and the local path gets a trailing slash, as in [`rsync_command`].
+///
+/// # Arguments
+/// * `rsync` - The rsync settings.
+/// * `secret` - The name of the secret holding the private key.
+fn action_inputs(rsync: &RsyncSettings, secret: &str) -> String {
+    let mut local_path = rsync.local_path.clone();
+    if !local_path.ends_with('/') {
+        local_path.push('/');
+    }
+    let switches = if rsync.delete {
+        "-avz --delete"
+    } else {
+        "-avz"
+    };
+
+    format!(
+        "with:
+  switches: {switches}
+  path: {path}
+  remote_path: {remote_path}
+  remote_host: {host}
+  remote_user: {user}
+  remote_key: \"${{{{ secrets.{secret} }}}}\"
+",
+        switches = switches,
+        path = yaml_quote(&local_path),
+        remote_path = yaml_quote(&rsync.remote_path),
+        host = yaml_quote(&rsync.host),
+        user = yaml_quote(&rsync.user),
+        secret = secret,
+    )
+}
+
+/// Asks the user for a value on stdin until a non-empty one is entered.
+///
+/// # Arguments
+/// * `question` - The question to display.
+///
+/// # Returns
+/// A `Result<String, AppError>` with the trimmed answer, or an
+/// `InvalidArgument` error if stdin is closed first.
+fn prompt_value(question: &str) -> Result<String, AppError> {
+    loop {
+        eprintln!("{}", question);
+        let mut answer = String::new();
+        if stdin().read_line(&mut answer)? == 0 {
+            return Err(invalid_argument(format!(
+                "no answer given for: {}",
+                question
+            )));
+        }
+
+        let answer = answer.trim();
+        if !answer.is_empty() {
+            return Ok(answer.to_string());
+        }
+    }
+}
+
+/// Finds the `owner/repo` of the current git repository's `origin` remote,
+/// for `--comment-from-git`.
+///
+/// This only makes the comment more descriptive, so when there is no git,
+/// repository or `origin` remote, a warning is printed and the usual comment
+/// is used.
+///
+/// # Returns
+/// An `Option<String>` with `owner/repo`, or `None` if it couldn't be found.
+fn git_origin_repo() -> Option<String> {
+    let output = match Command::new("git")
+        .args(["remote", "get-url", "origin"])
+        .stdin(Stdio::null())
+        .output()
+    {
+        Ok(output) => output,
+        Err(err) => {
+            log_warning(&format!(
+                "could not run git ({}); using the default key comment.",
+                err
+            ));
+            return None;
+        }
+    };
+    if !output.status.success() {
+        log_warning(
+            "--comment-from-git needs a git repository with an `origin` remote; using the \
+             default key comment.",
+        );
+        return None;
+    }
+
+    let url = String::from_utf8_lossy(&output.stdout);
+    let repo = parse_remote_repo(url.trim());
+    if repo.is_none() {
+        log_warning(&format!(
+            "could not find owner/repo in the origin URL {}; using the default key comment.",
+            url.trim()
+        ));
+    }
+    repo
+}
+
+/// Extracts `owner/repo` from a git remote URL, such as
+/// `https://github.com/owner/repo.git` or `git@github.com:owner/repo.git`.
+///
+/// # Arguments
+/// * `url` - The remote URL.
+///
+/// # Returns
+/// An `Option<String>` with `owner/repo`, or `None` if the URL doesn't name
+/// a host, owner and repository.
+fn parse_remote_repo(url: &str) -> Option<String> {
+    let url = url.trim_end_matches('/');
+    let url = url.strip_suffix(".git").unwrap_or(url);
+    let mut parts = url.rsplit(['/', ':']);
+    let repo = parts.next().filter(|repo| !repo.is_empty())?;
+    let owner = parts.next().filter(|owner| !owner.is_empty())?;
+    // The host comes before the owner; without it, the "owner" is the host.
+    parts.next().filter(|host| !host.is_empty())?;
+    Some(format!("{}/{}", owner, repo))
+}
+
+/// Derives the GitHub secret name for a key, e.g. `SSH_PRIVATE_KEY_DEPLOY`
+/// for a key named `deploy`.
+///
+/// Secret names may only contain letters, digits and underscores, so other
+/// characters are replaced with underscores.
+fn secret_name(key_name: &str) -> String {
+    let suffix: String = key_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("SSH_PRIVATE_KEY_{}", suffix)
+}
+
+/// Quotes a value as a single-quoted YAML scalar.
+fn yaml_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Writes a workflow for the CI service that deploys the repository with
+/// rsync over SSH using the generated key. See [`CiProvider::pipeline`].
+///
+/// # Arguments
+/// * `workflow` - The workflow settings.
+/// * `ci` - The CI service to write the workflow for.
+/// * `secret` - The name of the secret holding the private key.
+///
+/// # Returns
+/// An `io::Result<()>` indicating success or failure.
+fn emit_workflow(workflow: &WorkflowSettings, ci: CiProvider, secret: &str) -> io::Result<()> {
+    let path = resolve_home_path(&workflow.path)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let contents = ci.pipeline(
+        &workflow.host,
+        &workflow.user,
+        &workflow.remote_path,
+        secret,
+    );
+    std::fs::write(path, contents)?;
+
+    log_success(&format!("Workflow written to {}.", workflow.path));
+
+    Ok(())
+}
+
+/// A repository to upload the private key to as a secret, for `--repo`.
+struct GitHubSecretTarget {
+    /// The repository, as `owner/name`.
+    repo: String,
+    /// The API token, from `GITHUB_TOKEN`.
+    token: String,
+}
+
+/// A repository's public key, used to encrypt secrets before uploading them.
+#[derive(Deserialize)]
+struct RepoPublicKey {
+    /// The ID to send back with the encrypted secret.
+    key_id: String,
+    /// The base64-encoded Curve25519 public key.
+    key: String,
+}
+
+/// The request body for creating or updating a repository secret.
+#[derive(Serialize)]
+struct SecretUpload<'a> {
+    /// The base64-encoded sealed secret value.
+    encrypted_value: &'a str,
+    /// The ID of the public key the value was sealed with.
+    key_id: &'a str,
+}
+
+/// The body of a GitHub API error response.
+#[derive(Deserialize)]
+struct GitHubErrorBody {
+    /// GitHub's description of the error.
+    message: String,
+}
+
+/// Collects the settings for uploading the key with `--repo`.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+///
+/// # Returns
+/// A `Result<Option<GitHubSecretTarget>, AppError>` that is `None` when no
+/// upload was requested, or an `InvalidArgument` error if the repository is
+/// malformed or `GITHUB_TOKEN` isn't set.
+fn resolve_github_target(args: &Args) -> Result<Option<GitHubSecretTarget>, AppError> {
+    let Some(repo) = &args.repo else {
+        return Ok(None);
+    };
+    if let Some(ci @ (CiProvider::Gitlab | CiProvider::Bitbucket)) = args.ci {
+        return Err(invalid_argument(format!(
+            "--repo uploads to GitHub, not to --ci {}",
+            ci.to_possible_value().unwrap().get_name()
+        )));
+    }
+
+    let valid = match repo.split_once('/') {
+        Some((owner, name)) => !owner.is_empty() && !name.is_empty() && !name.contains('/'),
+        None => false,
+    };
+    if !valid {
+        return Err(invalid_argument(format!(
+            "invalid repository '{}' for --repo (expected owner/name)",
+            repo
+        )));
+    }
+
+    let token = env::var("GITHUB_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| {
+            invalid_argument(
+                "--repo requires a GITHUB_TOKEN with permission to write repository secrets"
+                    .to_string(),
+            )
+        })?;
+
+    Ok(Some(GitHubSecretTarget {
+        repo: repo.clone(),
+        token,
+    }))
+}
+
+/// Returns the base URL of the GitHub REST API, honouring `GITHUB_API_URL`
+/// for GitHub Enterprise Server.
+fn github_api_url() -> String {
+    env::var("GITHUB_API_URL")
+        .ok()
+        .filter(|url| !url.is_empty())
+        .unwrap_or_else(|| GITHUB_API_URL.to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// Builds an HTTP agent that reports error statuses as responses, so the
+/// API's own error messages can be shown.
+fn github_agent() -> ureq::Agent {
+    ureq::Agent::config_builder()
+        .http_status_as_error(false)
+        .build()
+        .into()
+}
+
+/// Adds the headers every GitHub API request needs.
+fn github_request<B>(
+    request: ureq::RequestBuilder<B>,
+    target: &GitHubSecretTarget,
+) -> ureq::RequestBuilder<B> {
+    request
+        .header("Accept", "application/vnd.github+json")
+        .header("Authorization", &format!("Bearer {}", target.token))
+        .header("X-GitHub-Api-Version", "2022-11-28")
+        .header(
+            "User-Agent",
+            &format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        )
+}
+
+/// Fetches the public key that repository secrets must be encrypted with.
+///
+/// This runs before the key is generated, so a bad token or missing
+/// permission is reported before anything is created.
+///
+/// # Arguments
+/// * `target` - The repository and token to use.
+///
+/// # Returns
+/// A `Result<RepoPublicKey, AppError>` with a `GitHubApi` error if the
+/// request fails.
+fn fetch_repo_public_key(target: &GitHubSecretTarget) -> Result<RepoPublicKey, AppError> {
+    let url = format!(
+        "{}/repos/{}/actions/secrets/public-key",
+        github_api_url(),
+        target.repo
+    );
+    let mut response = github_request(github_agent().get(&url), target)
+        .call()
+        .map_err(|err| AppError::GitHubApi(format!("could not reach GitHub ({})", err)))?;
+
+    let status = response.status().as_u16();
+    if status != 200 {
+        return Err(github_error(status, response.body_mut(), target));
+    }
+
+    response
+        .body_mut()
+        .read_json()
+        .map_err(|err| AppError::GitHubApi(format!("unexpected public key response ({})", err)))
+}
+
+/// Encrypts a secret value as a libsodium sealed box, as GitHub requires.
+///
+/// # Arguments
+/// * `public_key` - The repository's public key.
+/// * `value` - The secret value to encrypt.
+///
+/// # Returns
+/// A `Result<String, AppError>` with the base64-encoded sealed value.
+fn seal_secret(public_key: &RepoPublicKey, value: &str) -> Result<String, AppError> {
+    let key = BASE64_STANDARD
+        .decode(&public_key.key)
+        .ok()
+        .and_then(|key| crypto_box::PublicKey::from_slice(&key).ok())
+        .ok_or_else(|| {
+            AppError::GitHubApi("the repository's public key is malformed".to_string())
+        })?;
+
+    let sealed = key
+        .seal(&mut OsRng, value.as_bytes())
+        .map_err(|_| AppError::GitHubApi("could not encrypt the secret".to_string()))?;
+    Ok(BASE64_STANDARD.encode(sealed))
+}
+
+/// Creates or updates a repository secret holding `value`.
+///
+/// # Arguments
+/// * `target` - The repository and token to use.
+/// * `public_key` - The repository's public key, from
+///   [`fetch_repo_public_key`].
+/// * `secret` - The name of the secret to create or update.
+/// * `value` - The secret value to upload.
+///
+/// # Returns
+/// A `Result<(), AppError>` with a `GitHubApi` error if the upload fails.
+fn upload_github_secret(
+    target: &GitHubSecretTarget,
+    public_key: &RepoPublicKey,
+    secret: &str,
+    value: &str,
+) -> Result<(), AppError> {
+    let encrypted_value = seal_secret(public_key, value)?;
+    let url = format!(
+        "{}/repos/{}/actions/secrets/{}",
+        github_api_url(),
+        target.repo,
+        secret
+    );
+    let mut response = github_request(github_agent().put(&url), target)
+        .send_json(SecretUpload {
+            encrypted_value: &encrypted_value,
+            key_id: &public_key.key_id,
+        })
+        .map_err(|err| AppError::GitHubApi(format!("could not reach GitHub ({})", err)))?;
+
+    match response.status().as_u16() {
+        201 | 204 => Ok(()),
+        status => Err(github_error(status, response.body_mut(), target)),
+    }
+}
+
+/// Turns a failed GitHub API response into an error with a hint on how to
+/// fix it.
+///
+/// # Arguments
+/// * `status` - The HTTP status code.
+/// * `body` - The response body, which usually holds GitHub's message.
+/// * `target` - The repository the request was for.
+fn github_error(status: u16, body: &mut ureq::Body, target: &GitHubSecretTarget) -> AppError {
+    let hint = match status {
+        401 => "GitHub rejected the token; check that GITHUB_TOKEN is valid and not expired"
+            .to_string(),
+        403 => "the token may not manage secrets; it needs the `repo` scope, or \"Secrets\" \
+                read and write access for fine-grained tokens"
+            .to_string(),
+        404 => format!(
+            "repository {} was not found, or the token can't access it",
+            target.repo
+        ),
+        422 => "GitHub rejected the secret; names may only contain letters, digits and \
+                underscores"
+            .to_string(),
+        _ => format!("GitHub returned HTTP {}", status),
+    };
+
+    match body.read_json::<GitHubErrorBody>() {
+        Ok(body) => AppError::GitHubApi(format!("{} ({})", hint, body.message)),
+        Err(_) => AppError::GitHubApi(hint),
+    }
+}
+
+/// Returns the file to write a key's private key to: `--output`, or
+/// `--output-template` filled in for the key.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+/// * `key_name` - The name of the key.
+///
+/// # Returns
+/// A `Result<Option<String>, AppError>` that is `None` when the key isn't
+/// written to a file, or an `InvalidArgument` error for a bad template.
+fn private_key_output(args: &Args, key_name: &str) -> Result<Option<String>, AppError> {
+    match (&args.output, &args.output_template) {
+        (Some(output), _) => Ok(Some(output.clone())),
+        (None, Some(template)) => {
+            render_output_template(template, key_name, args.key_type()).map(Some)
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// Fills in an `--output-template`, replacing `{name}` with the key name,
+/// `{type}` with the key type and `{date}` with today's date.
+///
+/// The result may point into another directory, but not out of one with
+/// `..`, so a template can't be used to write somewhere unexpected.
+///
+/// # Arguments
+/// * `template` - The template, e.g. `{name}.pem`.
+/// * `key_name` - The name of the key.
+/// * `key_type` - The type of the key.
+///
+/// # Returns
+/// A `Result<String, AppError>` with the path, or an `InvalidArgument` error
+/// for an unknown or unclosed placeholder, or a path that is empty or
+/// contains `..`.
+fn render_output_template(
+    template: &str,
+    key_name: &str,
+    key_type: KeyType,
+) -> Result<String, AppError> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            return Err(invalid_argument(format!(
+                "--output-template has an unclosed {{ in '{}'",
+                template
+            )));
+        };
+        match &rest[start + 1..start + end] {
+            "name" => rendered.push_str(key_name),
+            "type" => rendered.push_str(key_type.as_str()),
+            "date" => rendered.push_str(
+                &chrono::Local::now()
+                    .date_naive()
+                    .format(EXPIRY_FORMAT)
+                    .to_string(),
+            ),
+            placeholder => {
+                return Err(invalid_argument(format!(
+                    "unknown placeholder {{{}}} in --output-template; use {{name}}, {{type}} or {{date}}",
+                    placeholder
+                )))
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    rendered.push_str(rest);
+
+    let path = Path::new(&rendered);
+    if rendered.trim().is_empty()
+        || path
+            .components()
+            .any(|component| component == Component::ParentDir)
+    {
+        return Err(invalid_argument(format!(
+            "--output-template gives '{}', which is not a safe path for the private key",
+            rendered
+        )));
+    }
+
+    Ok(rendered)
+}
+
+/// Checks that an output file either doesn't exist yet or may be replaced.
+///
+/// # Arguments
+/// * `path` - The file to check. A leading `~` is expanded.
+/// * `force` - Whether an existing file may be replaced.
+///
+/// # Returns
+/// A `Result<(), AppError>` with an `InvalidArgument` error if the file
+/// exists and `force` is not set.
+fn ensure_can_write(path: &str, force: bool) -> Result<(), AppError> {
+    if !force && resolve_home_path(path)?.exists() {
+        return Err(invalid_argument(format!(
+            "{} already exists; pass --force to replace it",
+            path
+        )));
+    }
+
+    Ok(())
+}
+
+/// Writes sensitive contents to a file that only its owner can read.
+///
+/// On Unix the file is created with mode `0600` so the contents are never
+/// readable by others, even briefly.
+///
+/// # Arguments
+/// * `path` - The file to write. A leading `~` is expanded.
+/// * `contents` - The contents to write.
+///
+/// # Returns
+/// An `io::Result<()>` indicating success or failure.
+fn write_private_file(path: &str, contents: &str) -> io::Result<()> {
+    let path = resolve_home_path(path)?;
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(&path)?;
+    file.write_all(contents.as_bytes())?;
+    restrict_permissions(&path, 0o600)?;
+
+    Ok(())
+}
+
+/// How long the key is kept on the clipboard on Linux, where clipboard
+/// contents disappear once the owning process exits.
+#[cfg(target_os = "linux")]
+const CLIPBOARD_HOLD_SECS: u64 = 60;
+
+/// Copies the private key to the system clipboard, byte-for-byte.
+///
+/// On Linux the clipboard is only served while this process runs, so this
+/// blocks for up to `CLIPBOARD_HOLD_SECS` seconds, or until something else
+/// is copied, to give the user time to paste.
+///
+/// # Arguments
+/// * `private_key` - The exact private key contents.
+///
+/// # Returns
+/// A `Result<(), arboard::Error>`, failing when no clipboard is available
+/// (e.g. on a headless CI runner).
+fn copy_to_clipboard(private_key: &str) -> Result<(), arboard::Error> {
+    let mut clipboard = arboard::Clipboard::new()?;
+
+    #[cfg(target_os = "linux")]
+    {
+        use arboard::SetExtLinux;
+        use std::time::{Duration, Instant};
+
+        log_success(&format!(
+            "Private key copied to the clipboard; paste it into GitHub Secrets within {} seconds.",
+            CLIPBOARD_HOLD_SECS
+        ));
+        clipboard
+            .set()
+            .wait_until(Instant::now() + Duration::from_secs(CLIPBOARD_HOLD_SECS))
+            .text(private_key)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        clipboard.set_text(private_key)?;
+        log_success("Private key copied to the clipboard; paste it into GitHub Secrets.");
+        Ok(())
+    }
+}
+
+/// Scans a server's host keys with `ssh-keyscan` and appends them to the
+/// `known_hosts` file in the SSH directory.
+///
+/// Like [`append_public_key_to_authorized_keys`], entries that are already
+/// present are not added again. The fingerprints of the scanned keys are
+/// printed so the user can compare them with the server's.
+///
+/// # Arguments
+/// * `ssh_dir` - The SSH directory containing `known_hosts`.
+/// * `host` - The server's host name or address.
+///
+/// # Returns
+/// A `Result<(), AppError>` with a `ConnectionFailed` error if no host keys
+/// could be retrieved.
+fn add_known_host(ssh_dir: &Path, host: &str) -> Result<(), AppError> {
+    let output = Command::new("ssh-keyscan")
+        .arg(host)
+        .output()
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => AppError::ConnectionFailed(
+                "ssh-keyscan not found; install OpenSSH client tools".to_string(),
+            ),
+            _ => AppError::Io(err),
+        })?;
+
+    let scanned = String::from_utf8_lossy(&output.stdout);
+    let host_keys: Vec<&str> = scanned
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    if host_keys.is_empty() {
+        return Err(AppError::ConnectionFailed(format!(
+            "ssh-keyscan returned no host keys for {}",
+            host
+        )));
+    }
+
+    // Show the fingerprints so they can be checked against the server's.
+    let mut fingerprint = Command::new("ssh-keygen")
+        .arg("-l")
+        .arg("-f")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(keygen_spawn_error)?;
+    if let Some(mut stdin) = fingerprint.stdin.take() {
+        stdin.write_all(host_keys.join("\n").as_bytes())?;
+    }
+    let fingerprints = fingerprint.wait_with_output()?;
+    log_info(&format!("Host key fingerprints for {}:", host));
+    for line in String::from_utf8_lossy(&fingerprints.stdout).lines() {
+        log_info(&format!("  {}", line));
+    }
+
+    let known_hosts_path = ssh_dir.join(KNOWN_HOSTS_FILE);
+    let existing = match std::fs::read_to_string(&known_hosts_path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+    let known: Vec<String> = existing.lines().map(normalize_key_line).collect();
+
+    let mut entry = String::new();
+    if !existing.is_empty() && !existing.ends_with('\n') {
+        entry.push('\n');
+    }
+    let mut added = 0;
+    for host_key in host_keys {
+        if !known.contains(&normalize_key_line(host_key)) {
+            entry.push_str(host_key);
+            entry.push('\n');
+            added += 1;
+        }
+    }
+
+    if added == 0 {
+        log_info(&format!(
+            "Host keys for {} already present in known_hosts.",
+            host
+        ));
+        return Ok(());
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&known_hosts_path)?
+        .write_all(entry.as_bytes())?;
+    log_success(&format!(
+        "Added {} host key(s) for {} to known_hosts.",
+        added, host
+    ));
+
+    Ok(())
+}
+
+/// Delay before the first retry of a transient `ssh-keygen` failure, in
+/// milliseconds. Each further retry waits twice as long.
+const RETRY_BASE_DELAY_MS: u64 = 250;
+
+/// Seconds to wait for the server during `--test-connection`.
+const CONNECT_TIMEOUT_SECS: u32 = 10;
+
+/// Checks that the generated key can log in to a server by running `true`
+/// over SSH.
+///
+/// Batch mode is used so that SSH fails instead of falling back to a
+/// password prompt, which means a passphrase-protected key must already be
+/// loaded in an agent.
+///
+/// # Arguments
+/// * `private_key_path` - The path to the private key.
+/// * `destination` - The server to log in to, as `user@host`.
+///
+/// # Returns
+/// A `Result<(), AppError>` with a `ConnectionFailed` error explaining the
+/// most likely cause when the login fails.
+fn test_connection(private_key_path: &Path, destination: &str) -> Result<(), AppError> {
+    log_info(&format!("Testing SSH connection to {}...", destination));
+
+    let output = Command::new("ssh")
+        .arg("-i")
+        .arg(private_key_path)
+        .arg("-o")
+        .arg("BatchMode=yes")
+        .arg("-o")
+        .arg("IdentitiesOnly=yes")
+        .arg("-o")
+        .arg(format!("ConnectTimeout={}", CONNECT_TIMEOUT_SECS))
+        .arg(destination)
+        .arg("true")
+        .output()
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => AppError::ConnectionFailed(
+                "ssh not found; install OpenSSH client tools".to_string(),
+            ),
+            _ => AppError::Io(err),
+        })?;
+
+    if output.status.success() {
+        log_success(&format!("Connection to {} succeeded.", destination));
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    let hint = ssh_failure_hint(
+        &stderr,
+        "the server rejected the key; is the public key in its authorized_keys?",
+    );
+    Err(AppError::ConnectionFailed(format!("{} ({})", hint, stderr)))
+}
+
+/// Picks a hint explaining why an `ssh` command failed, from its error
+/// output.
+///
+/// # Arguments
+/// * `stderr` - What `ssh` wrote to stderr.
+/// * `rejected` - The hint to give when the server refused authentication.
+fn ssh_failure_hint(stderr: &str, rejected: &'static str) -> &'static str {
+    if stderr.contains("Host key verification failed") {
+        "the server's host key is unknown or has changed; add it to known_hosts first"
+    } else if stderr.contains("timed out") {
+        "the server did not respond in time; check the host name and firewall"
+    } else if stderr.contains("Connection refused") {
+        "nothing is listening for SSH on the server"
+    } else if stderr.contains("Could not resolve hostname") {
+        "the host name could not be resolved"
+    } else if stderr.contains("Permission denied") {
+        rejected
+    } else {
+        "ssh reported an error"
+    }
+}
+
+/// Shell script run on the server by [`install_on_remote`]. It reads the
+/// public key from stdin, makes sure `~/.ssh/authorized_keys` exists with
+/// private permissions and a trailing newline, and appends the key unless
+/// an identical line is already there.
+const REMOTE_INSTALL_SCRIPT: &str = r#"umask 077
+mkdir -p ~/.ssh || exit 1
+keys=~/.ssh/authorized_keys
+touch "$keys" || exit 1
+key=$(cat)
+if grep -qxF "$key" "$keys"; then
+  echo present
+  exit 0
+fi
+if [ -s "$keys" ] && [ -n "$(tail -c 1 "$keys")" ]; then
+  echo >> "$keys"
+fi
+printf '%s\n' "$key" >> "$keys" && echo added"#;
+
+/// Appends the public key to `authorized_keys` on a remote server, like
+/// `ssh-copy-id` for just this key.
+///
+/// The login uses the user's existing SSH setup rather than the new key,
+/// and `ssh` may prompt for a password or host key confirmation on the
+/// terminal. The key is sent on stdin, so it never appears in the remote
+/// command line.
+///
+/// With `password_auth` only password logins are tried, to bootstrap a
+/// server that accepts no key yet. `ssh` reads the password from the
+/// terminal itself, so it never passes through this tool and is used for
+/// this one login only.
+///
+/// # Arguments
+/// * `public_key_path` - The path to the public key.
+/// * `destination` - The server to install on, as `user@host`.
+/// * `password_auth` - Whether to log in with a password instead of a key.
+///
+/// # Returns
+/// A `Result<(), AppError>` with a `ConnectionFailed` error if the key
+/// couldn't be installed.
+fn install_on_remote(
+    public_key_path: &Path,
+    destination: &str,
+    password_auth: bool,
+) -> Result<(), AppError> {
+    let public_key = read_key_file(public_key_path)?;
+    let public_key = public_key.trim();
+
+    log_info(&format!("Installing the public key on {}...", destination));
+
+    let mut command = Command::new("ssh");
+    command
+        .arg("-o")
+        .arg(format!("ConnectTimeout={}", CONNECT_TIMEOUT_SECS));
+    if password_auth {
+        command
+            .arg("-o")
+            .arg("PreferredAuthentications=password,keyboard-interactive")
+            .arg("-o")
+            .arg("PubkeyAuthentication=no");
+    }
+    command
+        .arg(destination)
+        // Run the script with `sh` whatever the remote login shell is.
+        .arg(format!("exec sh -c {}", shell_quote(REMOTE_INSTALL_SCRIPT)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    log_verbose(&format!("Running: {}", format_command(&command)));
+
+    let mut child = command.spawn().map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => {
+            AppError::ConnectionFailed("ssh not found; install OpenSSH client tools".to_string())
+        }
+        _ => AppError::Io(err),
+    })?;
+    if let Some(mut stdin) = child.stdin.take() {
+        writeln!(stdin, "{}", public_key)?;
+    }
+    let output = child.wait_with_output()?;
+    log_command_output(&output);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if output.status.success() {
+        if stdout.trim() == "present" {
+            log_info(&format!(
+                "The public key is already authorized on {}.",
+                destination
+            ));
+        } else {
+            log_success(&format!("Public key installed on {}.", destination));
+        }
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    let rejected = if password_auth {
+        "the server rejected the password, or doesn't allow password logins"
+    } else {
+        "the server rejected the login; check that you can already ssh to it"
+    };
+    let hint = ssh_failure_hint(&stderr, rejected);
+    let detail = if stderr.is_empty() {
+        format!("the remote command failed with {}", output.status)
+    } else {
+        stderr
+    };
+    Err(AppError::ConnectionFailed(format!("{} ({})", hint, detail)))
+}
+
+/// Expands a leading `~` in `path` to the user's home directory.
+///
+/// Both `~/` and, on Windows, `~\` prefixes are expanded. When the home
+/// directory can't be determined, a path that needs it is an error rather
+/// than being left with a literal `~` that later file operations would
+/// trip over.
+///
+/// # Arguments
+/// * `path` - The path to expand.
+///
+/// # Returns
+/// An `io::Result<PathBuf>` with the expanded path, or the error from
+/// [`home_dir`].
+fn resolve_home_path(path: &str) -> io::Result<PathBuf> {
+    if path == "~" {
+        return home_dir();
+    }
+
+    let rest = path.strip_prefix("~/");
+    #[cfg(windows)]
+    let rest = rest.or_else(|| path.strip_prefix("~\\"));
+    match rest {
+        Some(rest) => Ok(home_dir()?.join(rest)),
+        None => Ok(PathBuf::from(path)),
+    }
+}
+
+/// Expands `$NAME` and `${NAME}` environment variable references in a path.
+///
+/// # Arguments
+/// * `path` - The path to expand.
+///
+/// # Returns
+/// A `Result<String, AppError>` with the expanded path, or an
+/// `InvalidArgument` error naming a variable that isn't set.
+fn expand_env_vars(path: &str) -> Result<String, AppError> {
+    let mut expanded = String::new();
+    let mut rest = path;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, remainder) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => (braced, ""),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+
+        // A lone `$` isn't a reference, so keep it as it is.
+        if name.is_empty() {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let value = env::var(name)
+            .map_err(|_| invalid_argument(format!("${} in '{}' is not set", name, path)))?;
+        expanded.push_str(&value);
+        rest = remainder;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Returns the user's home directory, e.g. `%USERPROFILE%` on Windows.
+///
+/// # Returns
+/// An `io::Result<PathBuf>` with a `NotFound` error if the home directory
+/// can't be determined, as in some minimal containers.
+fn home_dir() -> io::Result<PathBuf> {
+    dirs::home_dir().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "the home directory could not be determined (is $HOME set?)",
+        )
+    })
+}
+
+/// Loads the private key into the running `ssh-agent` with `ssh-add`.
+///
+/// This is a convenience for trying the key out locally, so a missing agent
+/// or `ssh-add` is reported as a warning rather than an error. `ssh-add`
+/// inherits the terminal, so it can ask for the passphrase of a protected
+/// key itself.
+///
+/// # Arguments
+/// * `private_key_path` - The path to the private key.
+fn add_to_agent(private_key_path: &Path) {
+    if env::var_os("SSH_AUTH_SOCK").is_none() {
+        log_warning("no ssh-agent is running (SSH_AUTH_SOCK is not set); the key was not added.");
+        return;
+    }
+
+    let mut command = Command::new("ssh-add");
+    command.arg(private_key_path);
+    log_verbose(&format!("Running: {}", format_command(&command)));
+
+    match command.status() {
+        Ok(status) if status.success() => log_success("Key added to ssh-agent."),
+        Ok(status) => log_warning(&format!(
+            "ssh-add failed ({}); the key was not added to the agent.",
+            status
+        )),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            log_warning("ssh-add not found; skipped adding the key to the agent.")
+        }
+        Err(err) => log_warning(&format!("could not run ssh-add ({}).", err)),
+    }
+}
+
+/// A private temporary directory for `--ephemeral` keys, whose files are
+/// overwritten with zeros and deleted when it is dropped.
+///
+/// This is best effort: on `/dev/shm` the key never reaches a disk, but
+/// elsewhere journaling, copy-on-write file systems and SSDs may keep the
+/// old blocks around despite the overwrite.
+struct EphemeralDir {
+    /// The directory.
+    path: PathBuf,
+}
+
+impl EphemeralDir {
+    /// Creates the directory, with mode `0700`, under `/dev/shm` if it
+    /// exists (a RAM-backed file system on Linux), or the system's
+    /// temporary directory otherwise.
+    ///
+    /// # Returns
+    /// An `io::Result<EphemeralDir>` with the new directory.
+    fn create() -> io::Result<Self> {
+        let shm = Path::new("/dev/shm");
+        let base = if shm.is_dir() {
+            shm.to_path_buf()
+        } else {
+            env::temp_dir()
+        };
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.subsec_nanos());
+        let path = base.join(format!("sshcode-{}-{}", process::id(), nanos));
+
+        // Fails rather than reusing a directory someone else created.
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&path)?;
+        log_verbose(&format!("Generating keys in {}", path.display()));
+        Ok(EphemeralDir { path })
+    }
+}
+
+impl Drop for EphemeralDir {
+    fn drop(&mut self) {
+        let wipe = || -> io::Result<()> {
+            for entry in std::fs::read_dir(&self.path)? {
+                let path = entry?.path();
+                let len = std::fs::metadata(&path)?.len();
+                let mut file = OpenOptions::new().write(true).open(&path)?;
+                file.write_all(&vec![0; len as usize])?;
+                file.sync_all()?;
+                std::fs::remove_file(&path)?;
+            }
+            std::fs::remove_dir(&self.path)
+        };
+        match wipe() {
+            Ok(()) => log_info("Wiped and deleted the temporary key files."),
+            Err(err) => log_warning(&format!(
+                "could not wipe the temporary key files in {} ({}); delete them by hand.",
+                self.path.display(),
+                err
+            )),
+        }
+    }
+}
+
+/// Ensures that the `.ssh` directory exists, and the subdirectory for the
+/// key files in it if one is given.
+/// If a directory doesn't exist, it is created, unless `create` is `false`
+/// for environments that provision it themselves.
+///
+/// # Arguments
+/// * `ssh_dir` - The SSH directory.
+/// * `subdir` - A subdirectory of the SSH directory for the key files, such
+///   as `deploy-keys/prod`, if any.
+/// * `create` - Whether to create missing directories.
+/// * `dry_run` - Only report what would be done.
+///
+/// # Returns
+/// An `io::Result<()>` indicating success or failure, with a `NotFound`
+/// error for a missing directory that may not be created.
+pub fn ensure_ssh_directory_exists(
+    ssh_dir: &Path,
+    subdir: Option<&Path>,
+    create: bool,
+    dry_run: bool,
+) -> io::Result<()> {
+    // Every level gets the same treatment, so a nested directory is as
+    // private as the SSH directory itself.
+    let mut dir = ssh_dir.to_path_buf();
+    let mut levels = vec![dir.clone()];
+    for component in subdir.into_iter().flat_map(Path::components) {
+        dir.push(component);
+        levels.push(dir.clone());
+    }
+
+    for dir in &levels {
+        if !create && !dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "{} does not exist and directory creation is disabled",
+                    dir.display()
+                ),
+            ));
+        }
+        if dry_run {
+            if !dir.exists() {
+                eprintln!("Would create directory: {}", dir.display());
+            }
+            if cfg!(unix) {
+                eprintln!("Would restrict {} to mode 0700.", dir.display());
+            }
+            continue;
+        }
+
+        // Check if the directory exists, if not, create it.
+        if !dir.exists() {
+            std::fs::create_dir_all(dir)?;
+            log_info(&format!("Created directory: {}", dir.display()));
+        }
+
+        // SSH refuses to use keys from a directory others can access.
+        restrict_permissions(dir, 0o700)?;
+    }
+
+    Ok(())
+}
+
+/// Checks that a `--subdir` stays inside the SSH directory: it must be a
+/// relative path without `..`.
+///
+/// # Arguments
+/// * `subdir` - The subdirectory to check.
+///
+/// # Returns
+/// A `Result<(), AppError>` with an `InvalidArgument` error if the path is
+/// empty, absolute or contains `..`.
+fn validate_subdir(subdir: &Path) -> Result<(), AppError> {
+    let inside = subdir
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if subdir.as_os_str().is_empty() || !inside {
+        return Err(invalid_argument(format!(
+            "invalid --subdir '{}': use a relative path inside the SSH directory, without `..`",
+            subdir.display()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Creates the directory for an `--authorized-keys` file if it's missing.
+///
+/// Such a file often lives outside the home directory, e.g. under
+/// `/etc/ssh`, so the directory is only created with `--create-parents`.
+/// It is shared by the users whose keys it holds, so it gets mode `0755`
+/// rather than the SSH directory's `0700`. In dry-run mode nothing is
+/// created.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+/// * `authorized_keys_path` - The expanded `--authorized-keys` path.
+///
+/// # Returns
+/// A `Result<(), AppError>` that is a `NotFound` error naming the missing
+/// directory without `--create-parents`.
+fn ensure_authorized_keys_parent_exists(
+    args: &Args,
+    authorized_keys_path: &Path,
+) -> Result<(), AppError> {
+    let Some(parent) = authorized_keys_path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty() && !parent.exists())
+    else {
+        return Ok(());
+    };
+
+    if !args.create_parents {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "{} does not exist, so {} can't be written; pass --create-parents to create it",
+                parent.display(),
+                authorized_keys_path.display()
+            ),
+        )
+        .into());
+    }
+
+    if args.dry_run {
+        eprintln!("Would create directory: {}", parent.display());
+        if cfg!(unix) {
+            eprintln!("Would set {} to mode 0755.", parent.display());
+        }
+        return Ok(());
+    }
+
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o755);
+    builder.create(parent)?;
+    // The umask may have taken bits away; sshd only needs the directory to
+    // stay writable by its owner alone.
+    restrict_permissions(parent, 0o755)?;
+    log_info(&format!("Created directory: {}", parent.display()));
+    Ok(())
+}
+
+/// Sets the Unix permission bits of `path` to `mode`.
+///
+/// SSH ignores key files and directories that are readable by other users,
+/// so this is applied regardless of the process umask. On non-Unix platforms
+/// this is a no-op.
+///
+/// # Arguments
+/// * `path` - The file or directory to update.
+/// * `mode` - The permission bits, e.g. `0o600`.
+///
+/// # Returns
+/// An `io::Result<()>` indicating success or failure.
+#[cfg(unix)]
+fn restrict_permissions(path: &Path, mode: u32) -> io::Result<()> {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+/// Sets the Unix permission bits of `path` to `mode`.
+///
+/// On non-Unix platforms this is a no-op.
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Sets the process umask, which `ssh-keygen` inherits.
+///
+/// # Arguments
+/// * `mask` - The new umask, e.g. `0o077`.
+///
+/// # Returns
+/// The previous umask, to restore afterwards.
+#[cfg(unix)]
+fn set_umask(mask: u32) -> u32 {
+    // SAFETY: `umask` has no preconditions and cannot fail.
+    unsafe { libc::umask(mask as libc::mode_t) as u32 }
+}
+
+/// Sets the process umask. There is none outside Unix, so this does nothing.
+#[cfg(not(unix))]
+fn set_umask(_mask: u32) -> u32 {
+    0
+}
+
+/// Returns the Unix permission bits of `path`, such as `0o700`.
+///
+/// # Returns
+/// An `io::Result<Option<u32>>` that is `None` on non-Unix platforms.
+#[cfg(unix)]
+fn permission_bits(path: &Path) -> io::Result<Option<u32>> {
+    Ok(Some(std::fs::metadata(path)?.permissions().mode() & 0o777))
+}
+
+/// Returns the Unix permission bits of `path`.
+///
+/// On non-Unix platforms there are none, so this is always `None`.
+#[cfg(not(unix))]
+fn permission_bits(_path: &Path) -> io::Result<Option<u32>> {
+    Ok(None)
+}
+
+/// Warns when running as root, since a deploy key usually belongs to a
+/// service account rather than `/root/.ssh`.
+///
+/// In a container or CI job nobody sees the warning in time, so without a
+/// prompt this refuses to go on unless `--allow-root` or `--owner` is given.
+/// Dry runs change nothing and are always allowed.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+/// * `ssh_dir` - The SSH directory the keys would be written to.
+///
+/// # Returns
+/// A `Result<(), AppError>` that is an `InvalidArgument` error when running
+/// as root non-interactively without `--allow-root`.
+fn ensure_root_is_intended(args: &Args, ssh_dir: &Path) -> Result<(), AppError> {
+    // Handing the keys to another user is what root runs are for.
+    if !is_root() || args.allow_root || args.owner.is_some() {
+        return Ok(());
+    }
+
+    log_warning(&format!(
+        "running as root; keys will be written to {}.",
+        ssh_dir.display()
+    ));
+    if args.dry_run || args.is_interactive() {
+        return Ok(());
+    }
+
+    Err(invalid_argument(
+        "refusing to create keys as root in non-interactive mode; run as the user \
+         the key is for, or pass --allow-root"
+            .to_string(),
+    ))
+}
+
+/// The user, and group, to give the key files to with `--owner`.
+struct Owner {
+    /// The name as given, for messages.
+    name: String,
+    /// The user ID.
+    uid: u32,
+    /// The group ID.
+    gid: u32,
+}
+
+/// Looks up the user, and optionally the group, for `--owner`.
+///
+/// # Arguments
+/// * `spec` - `user`, for the user and their primary group, or
+///   `user:group`.
+///
+/// # Returns
+/// A `Result<Owner, AppError>` with an `InvalidArgument` error if the user
+/// or group doesn't exist.
+#[cfg(unix)]
+fn resolve_owner(spec: &str) -> Result<Owner, AppError> {
+    use std::ffi::CString;
+
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None),
+    };
+    let c_string = |name: &str| {
+        CString::new(name)
+            .map_err(|_| invalid_argument(format!("invalid name '{}' for --owner", name)))
+    };
+
+    let user_name = c_string(user)?;
+    // SAFETY: `getpwnam` gets a valid NUL-terminated string, and the record
+    // it points to is only read before the next lookup.
+    let (uid, primary_gid) = unsafe {
+        let entry = libc::getpwnam(user_name.as_ptr());
+        if entry.is_null() {
+            return Err(invalid_argument(format!(
+                "no such user '{}' for --owner",
+                user
+            )));
+        }
+        ((*entry).pw_uid, (*entry).pw_gid)
+    };
+
+    let gid = match group {
+        Some(group) => {
+            let group_name = c_string(group)?;
+            // SAFETY: as above, for `getgrnam`.
+            unsafe {
+                let entry = libc::getgrnam(group_name.as_ptr());
+                if entry.is_null() {
+                    return Err(invalid_argument(format!(
+                        "no such group '{}' for --owner",
+                        group
+                    )));
+                }
+                (*entry).gr_gid
+            }
+        }
+        None => primary_gid,
+    };
+
+    Ok(Owner {
+        name: spec.to_string(),
+        uid,
+        gid,
+    })
+}
+
+/// Looks up the user for `--owner`, which only exists on Unix.
+#[cfg(not(unix))]
+fn resolve_owner(_spec: &str) -> Result<Owner, AppError> {
+    Err(invalid_argument(
+        "--owner is only supported on Unix".to_string(),
+    ))
+}
+
+/// Gives a file or directory to the `--owner`.
+///
+/// # Arguments
+/// * `path` - The file or directory.
+/// * `owner` - The user and group to give it to.
+///
+/// # Returns
+/// An `io::Result<()>` indicating success or failure; changing the owner
+/// usually requires running as root.
+#[cfg(unix)]
+fn change_owner(path: &Path, owner: &Owner) -> io::Result<()> {
+    std::os::unix::fs::chown(path, Some(owner.uid), Some(owner.gid))
+}
+
+/// Gives a file to the `--owner`. [`resolve_owner`] already refuses outside
+/// Unix, so this is never reached there.
+#[cfg(not(unix))]
+fn change_owner(_path: &Path, _owner: &Owner) -> io::Result<()> {
+    Ok(())
+}
+
+/// Whether the tool runs with an effective user ID of 0.
+#[cfg(unix)]
+fn is_root() -> bool {
+    // SAFETY: `geteuid` has no preconditions and cannot fail.
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Whether the tool runs as root. There is no such user outside Unix.
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+/// Finds the `ssh-keygen` binary by probing each directory on `PATH`.
+///
+/// # Returns
+/// A `Result<PathBuf, AppError>` with the path to `ssh-keygen`, or
+/// `SshKeygenNotFound` if no directory on `PATH` contains it.
+fn locate_ssh_keygen() -> Result<PathBuf, AppError> {
+    find_on_path("ssh-keygen").ok_or(AppError::SshKeygenNotFound)
+}
+
+/// Finds a program by probing each directory on `PATH`, adding `.exe` to
+/// its name on Windows.
+///
+/// # Arguments
+/// * `name` - The program name, e.g. `ssh`.
+///
+/// # Returns
+/// The path to the program, or `None` if no directory on `PATH` has it.
+fn find_on_path(name: &str) -> Option<PathBuf> {
+    let binary = if cfg!(windows) {
+        format!("{}.exe", name)
+    } else {
+        name.to_string()
+    };
+
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(&binary))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Formats a command line for display, quoting arguments for the shell and
+/// masking the value passed to `-N` so that a passphrase never ends up on
+/// screen.
+fn format_command(command: &Command) -> String {
+    let mut parts = vec![command.get_program().to_string_lossy().to_string()];
+    let mut mask_next = false;
+    for arg in command.get_args() {
+        let arg = arg.to_string_lossy();
+        let part = if mask_next && !arg.is_empty() {
+            "<passphrase>".to_string()
+        } else {
+            shell_quote(&arg)
+        };
+        mask_next = arg == "-N";
+        parts.push(part);
+    }
+    parts.join(" ")
+}
+
+/// Quotes a value for a POSIX shell, leaving it as-is when it only contains
+/// characters the shell treats literally.
+fn shell_quote(value: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./-_~".contains(c);
+    if !value.is_empty() && value.chars().all(is_safe) {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+/// Echoes a finished command's exit status and captured output when
+/// `--verbose` is set.
+fn log_command_output(output: &process::Output) {
+    log_verbose(&format!("Command finished with {}", output.status));
+    for (name, captured) in [("stdout", &output.stdout), ("stderr", &output.stderr)] {
+        let captured = String::from_utf8_lossy(captured);
+        if !captured.trim().is_empty() {
+            log_verbose(&format!("{}:\n{}", name, captured.trim_end()));
+        }
+    }
+}
+
+/// Maps a failure to spawn `ssh-keygen` to an `AppError`, reporting a
+/// missing binary as `SshKeygenNotFound`.
+fn keygen_spawn_error(err: io::Error) -> AppError {
+    match err.kind() {
+        io::ErrorKind::NotFound => AppError::SshKeygenNotFound,
+        _ => AppError::Io(err),
+    }
+}
+
+/// Generates an SSH keypair with `ssh-keygen` as described by `config`,
+/// saving it in the SSH directory under the key name.
+///
+/// An existing key with the same name is not removed first, so
+/// [`setup_key`] is usually the better entry point.
+///
+/// # Arguments
+/// * `config` - The settings for the key.
+/// * `dry_run` - Only print the command that would be run.
+///
+/// # Returns
+/// A `Result<(), AppError>` indicating success or failure, or an
+/// `InvalidArgument` error for a key size `ssh-keygen` would reject.
+pub fn generate_ssh_key(config: &KeyConfig, dry_run: bool) -> Result<(), AppError> {
+    let bits = config.key_type.resolve_bits(config.bits, None)?;
+    config.key_type.check_strength(bits, config.allow_weak)?;
+    config.format.check_key_type(config.key_type)?;
+    run_ssh_keygen(config, bits, dry_run)
+}
+
+/// Runs `ssh-keygen` to generate the key described by `config`.
+///
+/// # Arguments
+/// * `config` - The settings for the key.
+/// * `bits` - The resolved key size to pass via `-b`, if any.
+/// * `dry_run` - Only print the command that would be run.
+///
+/// # Returns
+/// A `Result<(), AppError>` indicating success or failure.
+fn run_ssh_keygen(config: &KeyConfig, bits: Option<u32>, dry_run: bool) -> Result<(), AppError> {
+    let key_type = config.key_type;
+    let private_key_path = config.private_key_path();
+    let mut command = Command::new("ssh-keygen");
+    command.arg("-t").arg(key_type.as_str());
+
+    // Only pass a key size for key types that use one.
+    if let Some(bits) = bits {
+        command.arg("-b").arg(bits.to_string());
+    }
+
+    // The rounds only strengthen the passphrase, so there's nothing to apply
+    // them to without one.
+    if let (Some(rounds), false) = (config.kdf_rounds, config.passphrase.is_empty()) {
+        command.arg("-a").arg(rounds.to_string());
+    }
+
+    // `ssh-keygen` writes the newer OpenSSH format unless asked otherwise.
+    if config.format == KeyFormat::Pem {
+        command.arg("-m").arg("PEM");
+    }
+
+    command
+        .arg("-C")
+        .arg(config.comment.as_deref().unwrap_or(&config.name))
+        .arg("-f")
+        .arg(&private_key_path) // Save private key.
+        // The passphrase is passed as a single argument without a shell, so
+        // special characters need no escaping. Empty means no passphrase.
+        .arg("-N")
+        .arg(&config.passphrase)
+        // Never let a prompt, such as "Overwrite (y/n)?" from versions that
+        // ask about an existing file, wait on stdin: reading gets
+        // end-of-file and `ssh-keygen` fails straight away instead.
+        .stdin(Stdio::null());
+
+    if dry_run {
+        eprintln!("Would run: {}", format_command(&command));
+        return Ok(());
+    }
+
+    // `ssh-keygen`'s own touch prompt goes to the captured stderr, so give
+    // it here instead.
+    if key_type.is_security_key() {
+        log_info("Touch your security key when it blinks to authorize key generation.");
+    }
+
+    // Execute `ssh-keygen` to generate the SSH keypair.
+    log_verbose(&format!("Running: {}", format_command(&command)));
+    // Have `ssh-keygen` create the files private to begin with, rather than
+    // leaving them readable by others until the `chmod` below.
+    let previous_umask = set_umask(0o077);
+    let keygen_output = output_with_retries(&mut command, config.retries);
+    set_umask(previous_umask);
+    let keygen_output = keygen_output?;
+    log_command_output(&keygen_output);
+
+    if !keygen_output.status.success() {
+        let stderr = String::from_utf8_lossy(&keygen_output.stderr)
+            .trim()
+            .to_string();
+        if key_type.is_security_key() {
+            return Err(security_key_error(key_type, stderr));
+        }
+        return Err(AppError::KeygenFailed(stderr));
+    }
+
+    // A zero exit status isn't proof that the files ended up where they
+    // will be read from.
+    ensure_key_file_written(&private_key_path)?;
+    ensure_key_file_written(&config.public_key_path())?;
+
+    // Make sure the private key is only readable by its owner.
+    restrict_permissions(&private_key_path, 0o600)?;
+
+    log_success("SSH key generated successfully.");
+    Ok(())
+}
+
+/// Checks that `ssh-keygen` wrote a key file, so that a platform quirk such
+/// as writing somewhere else is reported clearly instead of as a confusing
+/// read error later on.
+///
+/// # Arguments
+/// * `path` - The key file `ssh-keygen` was asked to write.
+///
+/// # Returns
+/// A `Result<(), AppError>` with a `KeygenFailed` error naming the file if
+/// it is missing or empty.
+fn ensure_key_file_written(path: &Path) -> Result<(), AppError> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.len() > 0 => Ok(()),
+        Ok(_) => Err(AppError::KeygenFailed(format!(
+            "ssh-keygen reported success but {} is empty",
+            path.display()
+        ))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Err(AppError::KeygenFailed(format!(
+            "ssh-keygen reported success but did not create {}",
+            path.display()
+        ))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Runs a command and collects its output, starting it again with a growing
+/// delay when that fails for a transient reason.
+///
+/// Only failures to start the process are retried; a command that runs and
+/// fails is reported as it is, since running it again would fail the same
+/// way.
+///
+/// # Arguments
+/// * `command` - The command to run.
+/// * `retries` - How many more times to try after a transient failure.
+///
+/// # Returns
+/// A `Result<process::Output, AppError>` with the command's output, or the
+/// error from the last attempt.
+fn output_with_retries(command: &mut Command, retries: u32) -> Result<process::Output, AppError> {
+    let mut attempt = 0;
+    loop {
+        match command.output() {
+            Ok(output) => return Ok(output),
+            Err(err) if attempt < retries && is_transient_spawn_error(&err) => {
+                attempt += 1;
+                let delay = RETRY_BASE_DELAY_MS << (attempt - 1).min(4);
+                log_verbose(&format!(
+                    "Starting ssh-keygen failed ({}); retrying in {} ms ({} of {}).",
+                    err, delay, attempt, retries
+                ));
+                std::thread::sleep(std::time::Duration::from_millis(delay));
+            }
+            Err(err) => return Err(keygen_spawn_error(err)),
+        }
+    }
+}
+
+/// Whether starting a process failed for a reason that may go away on its
+/// own, like `EAGAIN` when a CI runner is out of processes or memory.
+fn is_transient_spawn_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::OutOfMemory
+    )
+}
+
+/// Explains a failure to generate a security key, which is usually down to
+/// the installed OpenSSH or a missing device rather than the key itself.
+///
+/// # Arguments
+/// * `key_type` - The security key type that was requested.
+/// * `stderr` - What `ssh-keygen` wrote to stderr.
+fn security_key_error(key_type: KeyType, stderr: String) -> AppError {
+    let hint = if stderr.contains("unknown key type") {
+        format!(
+            "this OpenSSH doesn't support {} keys; security keys need OpenSSH 8.2 or newer",
+            key_type
+        )
+    } else if stderr.contains("SecurityKeyProvider") || stderr.contains("without security key") {
+        "this OpenSSH was built without security key support".to_string()
+    } else if stderr.contains("device not found") {
+        "no security key was found; plug one in and try again".to_string()
+    } else {
+        return AppError::KeygenFailed(stderr);
+    };
+
+    AppError::KeygenFailed(format!("{} ({})", hint, stderr))
+}
+
+/// Checks that a public key file belongs to its private key by deriving the
+/// public key with `ssh-keygen -y` and comparing the two.
+///
+/// Only the key type and key data are compared, since the comment isn't part
+/// of the key.
+///
+/// # Arguments
+/// * `private_key_path` - The path to the private key.
+/// * `public_key_path` - The path to the public key that will be installed.
+/// * `passphrase` - The passphrase for the private key, empty for none.
+///
+/// # Returns
+/// A `Result<(), AppError>` that is a `KeyPairMismatch` error if the keys
+/// don't match, or `KeygenFailed` if the private key can't be read.
+pub fn verify_key_pair(
+    private_key_path: &Path,
+    public_key_path: &Path,
+    passphrase: &str,
+) -> Result<(), AppError> {
+    let mut command = Command::new("ssh-keygen");
+    command
+        .arg("-y")
+        .arg("-f")
+        .arg(private_key_path)
+        .arg("-P")
+        .arg(passphrase)
+        .stdin(Stdio::null());
+    log_verbose(&format!(
+        "Running: ssh-keygen -y -f {} -P ...",
+        shell_quote(&private_key_path.to_string_lossy())
+    ));
+    let output = command.output().map_err(keygen_spawn_error)?;
+
+    if !output.status.success() {
+        return Err(AppError::KeygenFailed(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    // Compare `<type> <base64>`, leaving out any trailing comment.
+    let derived = key_data(&String::from_utf8_lossy(&output.stdout));
+    let public_key = key_data(&std::fs::read_to_string(public_key_path)?);
+    if derived.is_empty() || derived != public_key {
+        return Err(AppError::KeyPairMismatch(public_key_path.to_path_buf()));
+    }
+
+    log_verbose("The public key matches the private key.");
+    Ok(())
+}
+
+/// The fingerprint details of a public key, as reported by `ssh-keygen -l`.
+struct KeyFingerprint {
+    /// The key size in bits.
+    bits: u32,
+    /// The fingerprint hash, e.g. `SHA256:...`.
+    hash: String,
+    /// The key type as `ssh-keygen` names it, e.g. `ED25519`.
+    key_type: String,
+}
+
+/// Computes the fingerprint of a public key using `ssh-keygen -lf`.
+///
+/// # Arguments
+/// * `public_key_path` - The path to the public key.
+///
+/// # Returns
+/// A `Result<KeyFingerprint, AppError>` with the parsed fingerprint, or a
+/// `KeygenFailed` error if `ssh-keygen` fails or its output can't be parsed.
+fn key_fingerprint(public_key_path: &Path) -> Result<KeyFingerprint, AppError> {
+    let output = Command::new("ssh-keygen")
+        .arg("-l")
+        .arg("-f")
+        .arg(public_key_path)
+        .output()
+        .map_err(keygen_spawn_error)?;
+
+    if !output.status.success() {
+        return Err(AppError::KeygenFailed(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    // The output looks like `4096 SHA256:... comment (RSA)`.
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut fields = stdout.split_whitespace();
+    let bits = fields.next().and_then(|bits| bits.parse().ok());
+    let hash = fields.next();
+    let key_type = fields
+        .last()
+        .and_then(|last| last.strip_prefix('('))
+        .and_then(|last| last.strip_suffix(')'));
+    match (bits, hash, key_type) {
+        (Some(bits), Some(hash), Some(key_type)) => Ok(KeyFingerprint {
+            bits,
+            hash: hash.to_string(),
+            key_type: key_type.to_string(),
+        }),
+        _ => Err(AppError::KeygenFailed(format!(
+            "unexpected fingerprint output: {}",
+            stdout.trim()
+        ))),
+    }
+}
+
+/// How a `--doctor` check turned out.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CheckStatus {
+    Pass,
+    // Something only some features need is missing.
+    Warn,
+    Fail,
+}
+
+/// The result of one `--doctor` check, with a fix when it didn't pass.
+struct DoctorCheck {
+    status: CheckStatus,
+    message: String,
+    fix: Option<String>,
+}
+
+impl DoctorCheck {
+    /// A check that passed.
+    fn pass(message: String) -> Self {
+        DoctorCheck {
+            status: CheckStatus::Pass,
+            message,
+            fix: None,
+        }
+    }
+
+    /// A check that didn't pass, with how to fix it.
+    fn problem(status: CheckStatus, message: String, fix: impl Into<String>) -> Self {
+        DoctorCheck {
+            status,
+            message,
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+/// The OpenSSH client programs some options need, with those options.
+const OPTIONAL_PROGRAMS: [(&str, &str); 3] = [
+    ("ssh", "--test-connection and --install-on"),
+    ("ssh-keyscan", "--add-known-host"),
+    ("ssh-add", "--add-to-agent"),
+];
+
+/// Checks the environment for the problems setups most often run into, for
+/// `--doctor`, and prints a pass/fail report with a fix for each problem.
+/// Nothing is created or changed.
+///
+/// The checks cover `ssh-keygen` and the other OpenSSH programs, the home
+/// directory, and the permissions of the SSH directory, `authorized_keys`
+/// and the private keys, which SSH refuses to use when others can access
+/// them.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+///
+/// # Returns
+/// A `Result<(), AppError>` that is a `ChecksFailed` error if any check
+/// failed. Warnings alone don't count as failures.
+fn run_doctor(args: &Args) -> Result<(), AppError> {
+    let mut checks = Vec::new();
+
+    checks.push(match locate_ssh_keygen() {
+        Ok(path) => DoctorCheck::pass(format!("ssh-keygen: {}", path.display())),
+        Err(_) => DoctorCheck::problem(
+            CheckStatus::Fail,
+            "ssh-keygen was not found on PATH".to_string(),
+            "install the OpenSSH client tools (e.g. `apt install openssh-client`)",
+        ),
+    });
+    for (program, needed_by) in OPTIONAL_PROGRAMS {
+        checks.push(match find_on_path(program) {
+            Some(path) => DoctorCheck::pass(format!("{}: {}", program, path.display())),
+            None => DoctorCheck::problem(
+                CheckStatus::Warn,
+                format!("{} was not found on PATH; {} need it", program, needed_by),
+                "install the OpenSSH client tools",
+            ),
+        });
+    }
+
+    // `dirs` falls back to the user database when `$HOME` isn't usable.
+    let home_set = env::var_os("HOME").is_some_and(|home| !home.is_empty());
+    checks.push(match (dirs::home_dir(), home_set || cfg!(windows)) {
+        (Some(home), true) => DoctorCheck::pass(format!("home directory: {}", home.display())),
+        (Some(home), false) => DoctorCheck::problem(
+            CheckStatus::Warn,
+            format!(
+                "$HOME is not set; using {} from the user database",
+                home.display()
+            ),
+            "set $HOME, or pass --ssh-dir",
+        ),
+        (None, _) => DoctorCheck::problem(
+            CheckStatus::Fail,
+            "the home directory could not be determined".to_string(),
+            "set $HOME, or pass --ssh-dir",
+        ),
+    });
+
+    if let Ok(ssh_dir) = resolve_ssh_dir(args) {
+        checks.push(check_ssh_dir(&ssh_dir)?);
+        let authorized_keys_path = resolve_authorized_keys_path(args, &ssh_dir)?;
+        checks.extend(check_permissions(
+            &authorized_keys_path,
+            0o022,
+            "writable by other users, so sshd ignores it",
+            "600",
+        )?);
+        let key_dir = match &args.subdir {
+            Some(subdir) => ssh_dir.join(subdir),
+            None => ssh_dir,
+        };
+        checks.extend(check_private_keys(&key_dir)?);
+    }
+
+    println!("{}", "Checking the environment:".bold());
+    for check in &checks {
+        let label = match check.status {
+            CheckStatus::Pass => "[ok]  ".green(),
+            CheckStatus::Warn => "[warn]".yellow(),
+            CheckStatus::Fail => "[FAIL]".red().bold(),
+        };
+        println!("  {} {}", label, check.message);
+        if let Some(fix) = &check.fix {
+            println!("         fix: {}", fix);
+        }
+    }
+
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == CheckStatus::Fail)
+        .count();
+    if failed > 0 {
+        return Err(AppError::ChecksFailed(failed));
+    }
+    log_success("All checks passed.");
+    Ok(())
+}
+
+/// Checks that the SSH directory, if it exists yet, is a directory other
+/// users can't access.
+///
+/// # Arguments
+/// * `ssh_dir` - The SSH directory.
+///
+/// # Returns
+/// An `io::Result<DoctorCheck>` with the outcome.
+fn check_ssh_dir(ssh_dir: &Path) -> io::Result<DoctorCheck> {
+    if !ssh_dir.exists() {
+        return Ok(DoctorCheck::pass(format!(
+            "SSH directory: {} (will be created with mode 0700)",
+            ssh_dir.display()
+        )));
+    }
+    if !ssh_dir.is_dir() {
+        return Ok(DoctorCheck::problem(
+            CheckStatus::Fail,
+            format!("{} is not a directory", ssh_dir.display()),
+            "move the file out of the way, or pass --ssh-dir",
+        ));
+    }
+
+    let fix = format!("chmod 700 {}", shell_quote(&ssh_dir.to_string_lossy()));
+    Ok(match permission_bits(ssh_dir)? {
+        Some(mode) if mode & 0o022 != 0 => DoctorCheck::problem(
+            CheckStatus::Fail,
+            format!(
+                "{} is writable by other users (mode {:04o}), so sshd ignores its keys",
+                ssh_dir.display(),
+                mode
+            ),
+            fix,
+        ),
+        Some(mode) if mode & 0o077 != 0 => DoctorCheck::problem(
+            CheckStatus::Warn,
+            format!(
+                "{} can be read by other users (mode {:04o})",
+                ssh_dir.display(),
+                mode
+            ),
+            fix,
+        ),
+        _ => DoctorCheck::pass(format!("SSH directory: {}", ssh_dir.display())),
+    })
+}
+
+/// Checks that a file, if it exists, has none of the `unsafe_bits` set.
+///
+/// # Arguments
+/// * `path` - The file to check.
+/// * `unsafe_bits` - The permission bits that must be clear, e.g. `0o077`.
+/// * `problem` - What it means when they aren't, e.g. "readable by others".
+/// * `mode` - The mode to suggest with `chmod`, e.g. `600`.
+///
+/// # Returns
+/// An `io::Result<Option<DoctorCheck>>` that is `None` when the file
+/// doesn't exist.
+fn check_permissions(
+    path: &Path,
+    unsafe_bits: u32,
+    problem: &str,
+    mode: &str,
+) -> io::Result<Option<DoctorCheck>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    Ok(Some(match permission_bits(path)? {
+        Some(bits) if bits & unsafe_bits != 0 => DoctorCheck::problem(
+            CheckStatus::Fail,
+            format!("{} is {} (mode {:04o})", path.display(), problem, bits),
+            format!("chmod {} {}", mode, shell_quote(&path.to_string_lossy())),
+        ),
+        _ => DoctorCheck::pass(format!("permissions of {}", path.display())),
+    }))
+}
+
+/// Checks that no other user can access the private keys in a directory,
+/// i.e. the files next to a `.pub` file of the same name.
+///
+/// # Arguments
+/// * `key_dir` - The directory with the key files.
+///
+/// # Returns
+/// An `io::Result<Vec<DoctorCheck>>` with one check per private key.
+fn check_private_keys(key_dir: &Path) -> io::Result<Vec<DoctorCheck>> {
+    let entries = match std::fs::read_dir(key_dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut private_keys: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "pub"))
+        .map(|public_key_path| public_key_path.with_extension(""))
+        .filter(|private_key_path| private_key_path.is_file())
+        .collect();
+    private_keys.sort();
+
+    let mut checks = Vec::new();
+    for private_key_path in private_keys {
+        checks.extend(check_permissions(
+            &private_key_path,
+            0o077,
+            "accessible by other users, so ssh refuses to use it",
+            "600",
+        )?);
+    }
+    Ok(checks)
+}
+
+/// Prints a table of the public keys in the SSH directory with their type,
+/// size and fingerprint.
+///
+/// Files that `ssh-keygen` can't read as a public key are skipped.
+///
+/// # Arguments
+/// * `ssh_dir` - The SSH directory to scan.
+///
+/// # Returns
+/// A `Result<(), AppError>` indicating success or failure.
+fn list_keys(ssh_dir: &Path) -> Result<(), AppError> {
+    let entries = match std::fs::read_dir(ssh_dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            println!("No keys found: {} does not exist.", ssh_dir.display());
+            return Ok(());
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut rows = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "pub") {
+            continue;
+        }
+        let Some(name) = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+        else {
+            continue;
+        };
+        if let Ok(fingerprint) = key_fingerprint(&path) {
+            rows.push((name, fingerprint));
+        }
+    }
+
+    if rows.is_empty() {
+        println!("No keys found in {}.", ssh_dir.display());
+        return Ok(());
+    }
+
+    rows.sort_by(|a, b| a.0.cmp(&b.0));
+    let name_width = rows
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    let type_width = rows
+        .iter()
+        .map(|(_, fingerprint)| fingerprint.key_type.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+
+    println!(
+        "{:<name_width$}  {:<type_width$}  {:>5}  FINGERPRINT",
+        "NAME", "TYPE", "BITS"
+    );
+    for (name, fingerprint) in rows {
+        println!(
+            "{:<name_width$}  {:<type_width$}  {:>5}  {}",
+            name, fingerprint.key_type, fingerprint.bits, fingerprint.hash
+        );
+    }
+
+    Ok(())
+}
+
+/// Appends the public SSH key to the `authorized_keys` file.
+///
+/// This allows SSH access using the newly generated public key. If the key
+/// is already listed, the file is left unchanged so repeated runs don't
+/// produce duplicate entries. Before a non-empty file is modified, it can be
+/// copied to `authorized_keys.bak` so the previous state is recoverable.
+///
+/// # Arguments
+/// * `authorized_keys_path` - The path to `authorized_keys`, normally in the
+///   SSH directory.
+/// * `public_key_path` - The path to the public key.
+/// * `options` - The `authorized_keys` options to put before the key, such
+///   as `no-pty`, if any.
+/// * `backup` - Whether to back up the existing file before modifying it.
+/// * `follow_symlinks` - Whether to write to the file `authorized_keys`
+///   links to, instead of refusing, when it is a symbolic link.
+/// * `dry_run` - Only report what would be done.
+///
+/// # Returns
+/// An `io::Result<bool>` that is `true` when the key was added, and `false`
+/// when it was already present or nothing was changed. A symbolic link is
+/// refused with a `PermissionDenied` error unless `follow_symlinks` is set.
+pub fn append_public_key_to_authorized_keys(
+    authorized_keys_path: &Path,
+    public_key_path: &Path,
+    options: Option<&str>,
+    backup: bool,
+    follow_symlinks: bool,
+    dry_run: bool,
+) -> io::Result<bool> {
+    let authorized_keys_path = &authorized_keys_target(authorized_keys_path, follow_symlinks)?;
+    if dry_run {
+        eprintln!(
+            "Would append {} to {} unless it is already present.",
+            public_key_path.display(),
+            authorized_keys_path.display()
+        );
+        if let Some(options) = options {
+            eprintln!("Would restrict the key with: {}", options);
+        }
+        if backup && authorized_keys_path.exists() {
+            eprintln!("Would back up {} first.", authorized_keys_path.display());
+        }
+        return Ok(false);
+    }
+
+    // Read the public key content.
+    let public_key = read_key_file(public_key_path)?;
+
+    // Skip the append if the key is already authorized.
+    if authorized_keys_contains(authorized_keys_path, &key_data(&public_key))? {
+        log_info("Public key already present in authorized_keys.");
+        return Ok(false);
+    }
+
+    let (len, ends_with_newline) = match std::fs::File::open(authorized_keys_path) {
+        Ok(mut file) => {
+            let len = file.metadata()?.len();
+            let mut last = [b'\n'];
+            if len > 0 {
+                file.seek(SeekFrom::End(-1))?;
+                file.read_exact(&mut last)?;
+            }
+            (len, last[0] == b'\n')
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (0, true),
+        Err(err) => return Err(err),
+    };
+
+    // Keep a copy of the previous state of this security-critical file.
+    if backup && len > 0 {
+        back_up_authorized_keys(authorized_keys_path)?;
+    }
+
+    // Add the public key after the existing entries, starting on a new line
+    // if the file doesn't end with one and ending with exactly one. The
+    // entries are copied over rather than read in, since the file can be
+    // large on a shared server.
+    let mut entry = String::new();
+    if let Some(options) = options {
+        entry.push_str(options);
+        entry.push(' ');
+    }
+    entry.push_str(public_key.trim_end());
+    entry.push('\n');
+    write_authorized_keys_with(authorized_keys_path, |file| {
+        if len > 0 {
+            io::copy(&mut std::fs::File::open(authorized_keys_path)?, file)?;
+            if !ends_with_newline {
+                file.write_all(b"\n")?;
+            }
+        }
+        file.write_all(entry.as_bytes())
+    })?;
+    log_success("Public key added to authorized_keys.");
+
+    Ok(true)
+}
+
+/// Makes sure `--replace-authorized-keys` is meant, since it locks out
+/// every other key in the file.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+/// * `authorized_keys_path` - The `authorized_keys` file to replace.
+///
+/// # Returns
+/// A `Result<(), AppError>` that is an `Aborted` error if replacing isn't
+/// confirmed, or `--force` is missing in non-interactive mode.
+fn confirm_replacing_authorized_keys(
+    args: &Args,
+    authorized_keys_path: &Path,
+) -> Result<(), AppError> {
+    let others = match std::fs::read_to_string(authorized_keys_path) {
+        Ok(contents) => contents
+            .lines()
+            .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .count(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+        Err(err) => return Err(err.into()),
+    };
+    if others == 0 {
+        return Ok(());
+    }
+
+    log_warning(&format!(
+        "--replace-authorized-keys removes the {} key(s) now in {}; anyone using them \
+         loses access.",
+        others,
+        authorized_keys_path.display()
+    ));
+    let question = format!(
+        "Replace every key in {} with the new one?",
+        authorized_keys_path.display()
+    );
+    if !confirm_destructive(args, &question)? {
+        return Err(AppError::Aborted(format!(
+            "{} was left untouched",
+            authorized_keys_path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Makes the public key the only entry in `authorized_keys`, for a clean
+/// slate on a freshly provisioned server. An existing file is always backed
+/// up first, since this removes every other key's access.
+///
+/// # Arguments
+/// * `authorized_keys_path` - The path to `authorized_keys`, normally in the
+///   SSH directory.
+/// * `public_key_path` - The path to the public key.
+/// * `options` - The `authorized_keys` options to put before the key, such
+///   as `no-pty`, if any.
+/// * `follow_symlinks` - Whether to write through a symbolic link.
+/// * `dry_run` - Only report what would be done.
+///
+/// # Returns
+/// An `io::Result<bool>` that is `true` when the file was changed, or
+/// `false` when it already held just this entry or nothing was changed.
+fn replace_authorized_keys(
+    authorized_keys_path: &Path,
+    public_key_path: &Path,
+    options: Option<&str>,
+    follow_symlinks: bool,
+    dry_run: bool,
+) -> io::Result<bool> {
+    let authorized_keys_path = &authorized_keys_target(authorized_keys_path, follow_symlinks)?;
+    if dry_run {
+        eprintln!(
+            "Would replace the contents of {} with {}.",
+            authorized_keys_path.display(),
+            public_key_path.display()
+        );
+        if authorized_keys_path.exists() {
+            eprintln!("Would back up {} first.", authorized_keys_path.display());
+        }
+        return Ok(false);
+    }
+
+    let public_key = read_key_file(public_key_path)?;
+    let contents = match options {
+        Some(options) => format!("{} {}\n", options, public_key.trim_end()),
+        None => format!("{}\n", public_key.trim_end()),
+    };
+
+    let existing = match std::fs::read_to_string(authorized_keys_path) {
+        Ok(existing) => existing,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    if existing == contents {
+        log_info("authorized_keys already holds just this key.");
+        return Ok(false);
+    }
+    if !existing.is_empty() {
+        back_up_authorized_keys(authorized_keys_path)?;
+    }
+
+    write_authorized_keys(authorized_keys_path, &contents)?;
+    log_success("authorized_keys now holds only the new key.");
+    Ok(true)
+}
+
+/// Returns the file to write for `authorized_keys`, which is the path itself
+/// unless it is a symbolic link.
+///
+/// Appending through a link planted by another user on a shared machine
+/// would put the key wherever the link points, so a link is refused unless
+/// `follow_symlinks` is set. Then its target is written instead, so the
+/// link itself is kept rather than replaced by a regular file.
+///
+/// # Arguments
+/// * `authorized_keys_path` - The expanded path to `authorized_keys`.
+/// * `follow_symlinks` - Whether to write through a symbolic link.
+///
+/// # Returns
+/// An `io::Result<PathBuf>` with the file to write, or a `PermissionDenied`
+/// error naming the link's target.
+fn authorized_keys_target(
+    authorized_keys_path: &Path,
+    follow_symlinks: bool,
+) -> io::Result<PathBuf> {
+    match std::fs::symlink_metadata(authorized_keys_path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {}
+        Ok(_) => return Ok(authorized_keys_path.to_path_buf()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(authorized_keys_path.to_path_buf())
+        }
+        Err(err) => return Err(err),
+    }
+
+    let link = std::fs::read_link(authorized_keys_path)?;
+    if !follow_symlinks {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is a symbolic link to {}; refusing to write through it \
+                 (pass --follow-symlinks if the link is intended)",
+                authorized_keys_path.display(),
+                link.display()
+            ),
+        ));
+    }
+
+    // A link to a file that doesn't exist yet can't be canonicalized, so
+    // resolve its target by hand, relative to the link's directory.
+    match std::fs::canonicalize(authorized_keys_path) {
+        Ok(target) => Ok(target),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(authorized_keys_path
+            .parent()
+            .map(|parent| parent.join(&link))
+            .unwrap_or(link)),
+        Err(err) => Err(err),
+    }
+}
+
+/// Checks whether `authorized_keys` already authorizes a key, reading it a
+/// line at a time and stopping at the first match, so a file with thousands
+/// of entries is never loaded whole.
+///
+/// # Arguments
+/// * `authorized_keys_path` - The path to `authorized_keys`.
+/// * `key` - The key to look for, as returned by [`key_data`].
+///
+/// # Returns
+/// An `io::Result<bool>` that is `true` when a line authorizes the key, and
+/// `false` when none does or the file doesn't exist.
+fn authorized_keys_contains(authorized_keys_path: &Path, key: &str) -> io::Result<bool> {
+    let file = match std::fs::File::open(authorized_keys_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+
+    for line in BufReader::new(file).split(b'\n') {
+        if authorizes_key(&String::from_utf8_lossy(&line?), key) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Replaces the contents of `authorized_keys` without ever leaving it
+/// half-written.
+///
+/// The new contents go to `authorized_keys.tmp`, which is synced to disk and
+/// then renamed over the original, so an interruption leaves either the old
+/// or the new file. The temporary file is created with mode `0600` on Unix
+/// and then takes over the original's permissions, so an existing file keeps
+/// its mode and a new one stays private to its owner.
+///
+/// # Arguments
+/// * `authorized_keys_path` - The expanded path to `authorized_keys`.
+/// * `contents` - The complete new contents of the file.
+///
+/// # Returns
+/// An `io::Result<()>` indicating success or failure.
+fn write_authorized_keys(authorized_keys_path: &Path, contents: &str) -> io::Result<()> {
+    write_authorized_keys_with(authorized_keys_path, |file| {
+        file.write_all(contents.as_bytes())
+    })
+}
+
+/// Replaces the contents of `authorized_keys` like [`write_authorized_keys`],
+/// with the new contents written by `fill` so they needn't be held in
+/// memory.
+///
+/// # Arguments
+/// * `authorized_keys_path` - The expanded path to `authorized_keys`.
+/// * `fill` - Writes the complete new contents to the temporary file.
+///
+/// # Returns
+/// An `io::Result<()>` indicating success or failure.
+fn write_authorized_keys_with(
+    authorized_keys_path: &Path,
+    fill: impl FnOnce(&mut std::fs::File) -> io::Result<()>,
+) -> io::Result<()> {
+    let temp_path = sibling_path(authorized_keys_path, "tmp");
+    let original_permissions = match std::fs::metadata(authorized_keys_path) {
+        Ok(metadata) => Some(metadata.permissions()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+
+    let result = (|| {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut temp_file = options.open(&temp_path)?;
+        fill(&mut temp_file)?;
+        temp_file.sync_all()?;
+        drop(temp_file);
+
+        match original_permissions {
+            Some(permissions) => std::fs::set_permissions(&temp_path, permissions)?,
+            None => restrict_permissions(&temp_path, 0o600)?,
+        }
+        std::fs::rename(&temp_path, authorized_keys_path)
+    })();
+
+    // Don't leave a stray copy of the keys behind if anything failed.
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Builds the `authorized_keys` options that restrict what the key may do,
+/// from `--restrict-command`, `--no-pty` and `--no-forwarding`.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+///
+/// # Returns
+/// A `Result<Option<String>, AppError>` with the comma-separated options,
+/// `None` if no restriction was asked for, or an `InvalidArgument` error for
+/// a command that can't be written on one line.
+fn authorized_key_options(args: &Args) -> Result<Option<String>, AppError> {
+    let mut options = Vec::new();
+    if let Some(command) = &args.restrict_command {
+        if command.trim().is_empty() || command.contains(['\n', '\r']) {
+            return Err(invalid_argument(
+                "--restrict-command must be a non-empty, single-line command".to_string(),
+            ));
+        }
+        // sshd only unescapes `\"` inside the quoted command.
+        options.push(format!("command=\"{}\"", command.replace('"', "\\\"")));
+    }
+    if args.no_pty {
+        options.push("no-pty".to_string());
+    }
+    if args.no_forwarding {
+        options.extend(
+            [
+                "no-port-forwarding",
+                "no-agent-forwarding",
+                "no-X11-forwarding",
+            ]
+            .map(String::from),
+        );
+    }
+
+    Ok((!options.is_empty()).then(|| options.join(",")))
+}
+
+/// Returns the path next to `path` with `.<extension>` added to its file
+/// name, e.g. `authorized_keys.bak`.
+fn sibling_path(path: &Path, extension: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(extension);
+    path.with_file_name(file_name)
+}
+
+/// Copies `authorized_keys` to `authorized_keys.bak` before it is modified.
+///
+/// # Arguments
+/// * `authorized_keys_path` - The expanded path to `authorized_keys`.
+///
+/// # Returns
+/// An `io::Result<()>` indicating success or failure.
+fn back_up_authorized_keys(authorized_keys_path: &Path) -> io::Result<()> {
+    let backup_path = sibling_path(authorized_keys_path, "bak");
+    std::fs::copy(authorized_keys_path, &backup_path)?;
+    restrict_permissions(&backup_path, 0o600)?;
+    log_info(&format!(
+        "Backed up authorized_keys to {}",
+        backup_path.display()
+    ));
+    Ok(())
+}
+
+/// Removes every line matching the given public key from `authorized_keys`.
+///
+/// Lines are matched with [`authorizes_key`], so an entry for the same key is
+/// removed whatever its options, but never one that merely shares a
+/// comment.
+///
+/// # Arguments
+/// * `authorized_keys_path` - The path to `authorized_keys`.
+/// * `public_key` - The public key contents to remove.
+/// * `backup` - Whether to back up the file before modifying it.
+/// * `follow_symlinks` - Whether to write through a symbolic link.
+///
+/// # Returns
+/// An `io::Result<usize>` with the number of lines removed.
+fn remove_public_key_from_authorized_keys(
+    authorized_keys_path: &Path,
+    public_key: &str,
+    backup: bool,
+    follow_symlinks: bool,
+) -> io::Result<usize> {
+    let authorized_keys_path = &authorized_keys_target(authorized_keys_path, follow_symlinks)?;
+    let existing = match std::fs::read_to_string(authorized_keys_path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+
+    let key = key_data(public_key);
+    let mut removed = 0;
+    let mut remaining = String::new();
+    for line in existing.lines() {
+        if authorizes_key(line, &key) {
+            removed += 1;
+        } else {
+            remaining.push_str(line);
+            remaining.push('\n');
+        }
+    }
+
+    if removed > 0 {
+        if backup {
+            back_up_authorized_keys(authorized_keys_path)?;
+        }
+        write_authorized_keys(authorized_keys_path, &remaining)?;
+    }
+
+    Ok(removed)
+}
+
+/// Prints the `authorized_keys` line for an existing key, for
+/// `--print-public-key`, so it can be added to a server without generating
+/// a new key. Any `--restrict-command`, `--no-pty` or `--no-forwarding`
+/// options are included, as with `--show-public-key`.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+/// * `ssh_dir` - The SSH directory containing the key.
+/// * `key_name` - The name of the key to print.
+///
+/// # Returns
+/// A `Result<(), AppError>` that is an `InvalidArgument` error if the key
+/// has no public key file.
+fn print_existing_public_key(args: &Args, ssh_dir: &Path, key_name: &str) -> Result<(), AppError> {
+    validate_key_name(key_name)?;
+
+    let public_key_path = KeyConfig::new(key_name, ssh_dir).public_key_path();
+    let public_key = match std::fs::read_to_string(&public_key_path) {
+        Ok(public_key) => public_key,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(invalid_argument(format!(
+                "no public key named '{}' in {} ({} does not exist)",
+                key_name,
+                ssh_dir.display(),
+                public_key_path.display()
+            )))
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let entry = match authorized_key_options(args)? {
+        Some(options) => format!("{} {}", options, public_key.trim_end()),
+        None => public_key.trim_end().to_string(),
+    };
+    print_public_key_block(&entry);
+
+    Ok(())
+}
+
+/// Checks that a key's files exist, for `--check`, so a provisioning script
+/// can skip creating a key it already has. With `--check-authorized`, the
+/// key must also be in `authorized_keys`. Nothing is changed, and the result
+/// is only described with `--verbose`.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+/// * `ssh_dir` - The SSH directory containing the key.
+/// * `authorized_keys_path` - The path to `authorized_keys`.
+/// * `key_name` - The name of the key to check.
+///
+/// # Returns
+/// A `Result<(), AppError>` that is a `CheckFailed` error with the reason if
+/// the key is missing or not authorized.
+fn check_key(
+    args: &Args,
+    ssh_dir: &Path,
+    authorized_keys_path: &Path,
+    key_name: &str,
+) -> Result<(), AppError> {
+    validate_key_name(key_name)?;
+
+    let fail = |reason: String| {
+        log_verbose(&reason);
+        Err(AppError::CheckFailed(reason))
+    };
+    let config = KeyConfig::new(key_name, ssh_dir);
+    for path in [config.private_key_path(), config.public_key_path()] {
+        if !path.is_file() {
+            return fail(format!("{} does not exist", path.display()));
+        }
+    }
+
+    if args.check_authorized {
+        let public_key = read_key_file(&config.public_key_path())?;
+        if !authorized_keys_contains(authorized_keys_path, &key_data(&public_key))? {
+            return fail(format!(
+                "the key '{}' is not in {}",
+                key_name,
+                authorized_keys_path.display()
+            ));
+        }
+    }
+
+    log_verbose(&format!("The key '{}' exists.", key_name));
+    Ok(())
+}
+
+/// Deletes a key's files and revokes it from `authorized_keys`.
+///
+/// The `authorized_keys` entry is matched by the contents of the key's
+/// `.pub` file. Since this is destructive, it needs confirmation or
+/// `--force`.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+/// * `ssh_dir` - The SSH directory containing the key.
+/// * `authorized_keys_path` - The path to `authorized_keys`.
+/// * `key_name` - The name of the key to remove.
+///
+/// # Returns
+/// A `Result<(), AppError>` indicating success or failure.
+fn remove_key(
+    args: &Args,
+    ssh_dir: &Path,
+    authorized_keys_path: &Path,
+    key_name: &str,
+) -> Result<(), AppError> {
+    validate_key_name(key_name)?;
+
+    let private_key_path = ssh_dir.join(key_name);
+    let public_key_path = ssh_dir.join(format!("{}.pub", key_name));
+    let public_key = match read_key_file(&public_key_path) {
+        Ok(public_key) => Some(public_key),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+    let private_key_exists = private_key_path.exists();
+
+    if public_key.is_none() && !private_key_exists {
+        return Err(invalid_argument(format!(
+            "no key named '{}' in {}",
+            key_name,
+            ssh_dir.display()
+        )));
+    }
+
+    if args.dry_run {
+        if private_key_exists {
+            eprintln!("Would delete {}", private_key_path.display());
+        }
+        if public_key.is_some() {
+            eprintln!("Would delete {}", public_key_path.display());
+            eprintln!("Would remove the key from authorized_keys.");
+        }
+        return Ok(());
+    }
+
+    let question = format!(
+        "Remove the key '{}' and revoke its access? This cannot be undone.",
+        key_name
+    );
+    if !confirm_destructive(args, &question)? {
+        return Err(AppError::Aborted("the key was left untouched".to_string()));
+    }
+
+    delete_key(
+        authorized_keys_path,
+        &private_key_path,
+        &public_key_path,
+        public_key.as_deref(),
+        !args.no_backup,
+        args.follow_symlinks,
+    )?;
+    Ok(())
+}
+
+/// Revokes a key from `authorized_keys` and deletes its files.
+///
+/// # Arguments
+/// * `authorized_keys_path` - The path to `authorized_keys`.
+/// * `private_key_path` - The path of the private key.
+/// * `public_key_path` - The path of the public key.
+/// * `public_key` - The public key contents, or `None` if the `.pub` file is
+///   missing and `authorized_keys` can't be matched against.
+/// * `backup` - Whether to back up `authorized_keys` before modifying it.
+/// * `follow_symlinks` - Whether to write through a symbolic link.
+///
+/// # Returns
+/// An `io::Result<usize>` with the number of `authorized_keys` entries
+/// removed.
+fn delete_key(
+    authorized_keys_path: &Path,
+    private_key_path: &Path,
+    public_key_path: &Path,
+    public_key: Option<&str>,
+    backup: bool,
+    follow_symlinks: bool,
+) -> io::Result<usize> {
+    // Revoke access first, while the public key is still around to match on.
+    let removed = match public_key {
+        Some(public_key) => {
+            let removed = remove_public_key_from_authorized_keys(
+                authorized_keys_path,
+                public_key,
+                backup,
+                follow_symlinks,
+            )?;
+            if removed > 0 {
+                log_success(&format!(
+                    "Removed {} entry(ies) from authorized_keys.",
+                    removed
+                ));
+            } else {
+                log_info("The key was not in authorized_keys.");
+            }
+            removed
+        }
+        None => {
+            log_warning(&format!(
+                "{} is missing, so authorized_keys was left unchanged.",
+                public_key_path.display()
+            ));
+            0
+        }
+    };
+
+    for path in [private_key_path, public_key_path] {
+        if path.exists() {
+            std::fs::remove_file(path)?;
+            log_success(&format!("Deleted {}", path.display()));
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Reads the expiry date that `--expire-days` put in a public key's comment.
+///
+/// Keys without a well-formed `expires=YYYY-MM-DD` annotation weren't given
+/// an expiry by this tool, so they have none.
+///
+/// # Arguments
+/// * `public_key` - The contents of the public key file.
+fn key_expiry(public_key: &str) -> Option<chrono::NaiveDate> {
+    // The comment is everything after the key type and key data.
+    public_key
+        .split_whitespace()
+        .skip(2)
+        .filter_map(|word| word.strip_prefix(EXPIRY_PREFIX))
+        .find_map(|date| chrono::NaiveDate::parse_from_str(date, EXPIRY_FORMAT).ok())
+}
+
+/// Lists the keys in the SSH directory whose expiry date has passed, then
+/// deletes them and revokes them from `authorized_keys`.
+///
+/// Only keys annotated by `--expire-days` are considered. Removal needs
+/// confirmation or `--force`, and in dry-run mode the keys are only listed.
+///
+/// # Arguments
+/// * `args` - The parsed command-line options.
+/// * `ssh_dir` - The SSH directory to scan.
+/// * `authorized_keys_path` - The path to `authorized_keys`.
+///
+/// # Returns
+/// A `Result<(), AppError>` indicating success or failure.
+fn prune_expired_keys(
+    args: &Args,
+    ssh_dir: &Path,
+    authorized_keys_path: &Path,
+) -> Result<(), AppError> {
+    let entries = match std::fs::read_dir(ssh_dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            log_info(&format!(
+                "No keys found: {} does not exist.",
+                ssh_dir.display()
+            ));
+            return Ok(());
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let today = chrono::Local::now().date_naive();
+    let mut expired = Vec::new();
+    for entry in entries {
+        let public_key_path = entry?.path();
+        if public_key_path
+            .extension()
+            .is_none_or(|extension| extension != "pub")
+        {
+            continue;
+        }
+        let Ok(public_key) = std::fs::read_to_string(&public_key_path) else {
+            continue;
+        };
+        if let Some(expires) = key_expiry(&public_key).filter(|expires| *expires < today) {
+            expired.push((public_key_path, public_key, expires));
+        }
+    }
+
+    if expired.is_empty() {
+        log_info(&format!("No expired keys in {}.", ssh_dir.display()));
+        return Ok(());
+    }
+
+    expired.sort_by(|a, b| a.0.cmp(&b.0));
+    println!("Expired keys:");
+    for (public_key_path, _, expires) in &expired {
+        println!(
+            "  {} (expired {})",
+            public_key_path.with_extension("").display(),
+            expires.format(EXPIRY_FORMAT)
+        );
+    }
+
+    if args.dry_run {
+        eprintln!("Would remove these keys and revoke them from authorized_keys.");
+        return Ok(());
+    }
+
+    let question = format!(
+        "Remove {} expired key(s) and revoke their access? This cannot be undone.",
+        expired.len()
+    );
+    if !confirm_destructive(args, &question)? {
+        return Err(AppError::Aborted(
+            "the keys were left untouched".to_string(),
+        ));
+    }
+
+    // Back up `authorized_keys` once, so the backup holds its state from
+    // before the prune rather than after all but the last key. Keys that
+    // aren't authorized don't change the file, so it is backed up by the
+    // first removal that does.
+    let mut backed_up = args.no_backup;
+    for (public_key_path, public_key, _) in &expired {
+        let removed = delete_key(
+            authorized_keys_path,
+            &public_key_path.with_extension(""),
+            public_key_path,
+            Some(public_key),
+            !backed_up,
+            args.follow_symlinks,
+        )?;
+        backed_up |= removed > 0;
+    }
+
+    Ok(())
+}
+
+/// Returns the `<type> <base64>` part of a public key line, leaving out the
+/// comment.
+fn key_data(public_key: &str) -> String {
+    public_key
+        .split_whitespace()
+        .take(2)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Whether an `authorized_keys` line grants access to a key, whatever
+/// options or comment the line has.
+///
+/// # Arguments
+/// * `line` - A line of `authorized_keys`.
+/// * `key_data` - The key's `<type> <base64>`, from [`key_data`].
+fn authorizes_key(line: &str, key_data: &str) -> bool {
+    // Options come before the key and may contain quoted spaces, so look for
+    // the type and key data as adjacent words anywhere in the line.
+    let words: Vec<&str> = line.split_whitespace().collect();
+    !line.trim_start().starts_with('#') && words.windows(2).any(|pair| pair.join(" ") == key_data)
+}
+
+/// Reads a key file, converting Windows (CRLF) line endings to LF, which is
+/// what SSH expects in `authorized_keys` and key files. When a conversion is
+/// needed, the original copy is wiped, since this also reads private keys.
+///
+/// # Arguments
+/// * `path` - The key file to read.
+///
+/// # Returns
+/// An `io::Result<String>` with the file contents.
+fn read_key_file(path: &Path) -> io::Result<String> {
+    let mut contents = std::fs::read_to_string(path)?;
+    if contents.contains("\r\n") {
+        let normalized = contents.replace("\r\n", "\n");
+        contents.zeroize();
+        contents = normalized;
+    }
+    Ok(contents)
+}
+
+/// Normalizes a public key line for comparison by collapsing runs of
+/// whitespace into single spaces and trimming both ends.
+fn normalize_key_line(line: &str) -> String {
+    line.split_whitespace().collect::<Vec<_>>().join(" ")
+}